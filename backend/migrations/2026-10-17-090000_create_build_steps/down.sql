@@ -0,0 +1 @@
+DROP TABLE build_steps;
\ No newline at end of file
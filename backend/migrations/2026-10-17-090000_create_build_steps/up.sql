@@ -0,0 +1,14 @@
+CREATE TABLE `build_steps` (
+  `build_step_id` int(11) NOT NULL,
+  `build_id` int(11) NOT NULL,
+  `step_index` int(11) NOT NULL,
+  `command` text NOT NULL,
+  `exit_code` int(11) DEFAULT NULL,
+  `exit_signal` int(11) DEFAULT NULL,
+  `started_at` datetime NOT NULL,
+  `finished_at` datetime NOT NULL,
+  `duration_ms` bigint(20) NOT NULL
+) ENGINE=InnoDB DEFAULT CHARSET=latin1;
+
+ALTER TABLE `build_steps` ADD PRIMARY KEY (`build_step_id`), ADD KEY `build_id` (`build_id`);
+ALTER TABLE `build_steps` CHANGE `build_step_id` `build_step_id` INT(11) NOT NULL AUTO_INCREMENT;
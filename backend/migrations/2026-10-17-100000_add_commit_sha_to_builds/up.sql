@@ -0,0 +1 @@
+ALTER TABLE `builds` ADD `commit_sha` varchar(40) DEFAULT NULL;
@@ -0,0 +1 @@
+ALTER TABLE `projects` ADD `default_branch` varchar(255) NOT NULL DEFAULT 'master';
@@ -0,0 +1 @@
+ALTER TABLE `builds` ADD `pr_number` int(11) DEFAULT NULL;
@@ -0,0 +1 @@
+ALTER TABLE `builds` ADD `tag` varchar(255) DEFAULT NULL;
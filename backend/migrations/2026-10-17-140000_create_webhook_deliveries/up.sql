@@ -0,0 +1,15 @@
+CREATE TABLE `webhook_deliveries` (
+  `webhook_delivery_id` int(11) NOT NULL,
+  `delivery_id` varchar(255) DEFAULT NULL,
+  `event` varchar(255) NOT NULL,
+  `headers` text NOT NULL,
+  `body` mediumtext NOT NULL,
+  `verified` tinyint(1) NOT NULL,
+  `processed` tinyint(1) NOT NULL,
+  `outcome` text NOT NULL,
+  `build_id` int(11) DEFAULT NULL,
+  `received_at` datetime NOT NULL
+) ENGINE=InnoDB DEFAULT CHARSET=latin1;
+
+ALTER TABLE `webhook_deliveries` ADD PRIMARY KEY (`webhook_delivery_id`), ADD KEY `delivery_id` (`delivery_id`);
+ALTER TABLE `webhook_deliveries` CHANGE `webhook_delivery_id` `webhook_delivery_id` INT(11) NOT NULL AUTO_INCREMENT;
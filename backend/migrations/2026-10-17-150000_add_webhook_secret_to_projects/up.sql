@@ -0,0 +1 @@
+ALTER TABLE `projects` ADD `webhook_secret` varchar(255) DEFAULT NULL;
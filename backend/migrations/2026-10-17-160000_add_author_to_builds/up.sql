@@ -0,0 +1 @@
+ALTER TABLE `builds` ADD `author` varchar(255) DEFAULT NULL;
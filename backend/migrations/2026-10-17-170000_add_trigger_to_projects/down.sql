@@ -0,0 +1 @@
+ALTER TABLE `projects` DROP COLUMN `trigger_token`, DROP COLUMN `trigger_mapping`;
@@ -0,0 +1 @@
+ALTER TABLE `projects` ADD `trigger_token` varchar(255) DEFAULT NULL, ADD `trigger_mapping` text DEFAULT NULL;
@@ -0,0 +1 @@
+ALTER TABLE `projects` ADD `poll_interval` int(11) DEFAULT NULL;
@@ -0,0 +1 @@
+ALTER TABLE `projects` ADD `build_forks` tinyint(1) NOT NULL DEFAULT 0;
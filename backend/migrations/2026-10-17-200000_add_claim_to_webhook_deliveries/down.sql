@@ -0,0 +1 @@
+ALTER TABLE `webhook_deliveries` DROP INDEX `claimed_delivery_id`, DROP COLUMN `claimed_delivery_id`;
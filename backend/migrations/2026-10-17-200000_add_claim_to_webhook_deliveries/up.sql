@@ -0,0 +1,7 @@
+ALTER TABLE `webhook_deliveries` ADD `claimed_delivery_id` varchar(255) DEFAULT NULL, ADD UNIQUE KEY `claimed_delivery_id` (`claimed_delivery_id`);
+
+UPDATE `webhook_deliveries` JOIN (
+  SELECT MIN(`webhook_delivery_id`) AS `first_id` FROM `webhook_deliveries`
+  WHERE `processed` = 1 AND `delivery_id` IS NOT NULL GROUP BY `delivery_id`
+) AS `processed_deliveries` ON `webhook_delivery_id` = `first_id`
+SET `claimed_delivery_id` = `delivery_id`;
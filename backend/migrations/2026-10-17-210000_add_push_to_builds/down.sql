@@ -0,0 +1,2 @@
+ALTER TABLE `builds` DROP COLUMN `changed_files`;
+ALTER TABLE `builds` DROP COLUMN `before_sha`;
@@ -0,0 +1,2 @@
+ALTER TABLE `builds` ADD `before_sha` varchar(40) DEFAULT NULL;
+ALTER TABLE `builds` ADD `changed_files` text DEFAULT NULL;
@@ -1,70 +1,121 @@
+// diesel 1.x derives and table! implement traits inside of generated functions
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate diesel;
 extern crate actix_web;
 extern crate env_logger;
 
-use std::process::{Command, Stdio};
+use std::io::Read;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, ffi::OsStr};
 use std::{env, fs};
 use std::{fs::File, io, path::Path};
 
 use actix_cors::Cors;
 use actix_web::http::HeaderMap;
-use diesel::{insert_into, prelude::*};
+use actix_web::web::Bytes;
+use chrono::NaiveDateTime;
+use diesel::{insert_into, prelude::*, update};
 
-use actix_web::{
-    middleware::{self, Logger},
-    App, HttpResponse, HttpServer,
-};
+use actix_web::rt::time;
+use actix_web::{middleware, web, App, HttpResponse, HttpServer};
 use badge::{Badge, BadgeOptions};
+use command::{
+    build_command, wait_for_step, BuildLog, CommandsOutcome, StepConfig, StepExit, StepResult,
+};
 use diesel::MysqlConnection;
+use futures::{stream, Stream};
 use hmac::{Hmac, Mac, NewMac};
-use models::{Build, Project};
+use models::{Build, BuildStatus, BuildStep, Project};
+use queue::BuildQueue;
 use routes::{
-    get_file_for_build, get_latest_file, get_latest_status_badge, get_project_information,
-    get_status_badge_for_build, github_webhook,
+    cancel_build, get_file_for_build, get_file_for_tag, get_latest_file, get_latest_status_badge,
+    get_project_information, get_status_badge_for_build, stream_build_log, with_connection, DbPool,
 };
 use serde::{Deserialize, Serialize};
+use sha1::Sha1;
 use sha2::Sha256;
 use std::error::Error;
+use triggers::{is_null_sha, GitRef, TriggerConfig};
+use webhooks::bitbucket::bitbucket_webhook;
+use webhooks::gitea::gitea_webhook;
+use webhooks::github::github_webhook;
+use webhooks::gitlab::gitlab_webhook;
+use webhooks::trigger::trigger_build;
+use workspace::{changed_files, fetch_commit, read_file_at, Workspace, WorkspaceCleanup};
 
 use diesel::r2d2::{self, ConnectionManager};
 
+use crate::schema::build_steps::dsl as step;
 use crate::schema::builds::dsl as build;
 use crate::schema::projects::dsl as proj;
 
+mod command;
 pub mod models;
+mod poller;
+mod queue;
 mod routes;
 pub mod schema;
+mod triggers;
+mod webhooks;
+mod workspace;
 
-type HmacSha256 = Hmac<Sha256>;
-
-/// Represents data taken from github webhook
-#[derive(Debug, Deserialize)]
-struct WebhookData {
-    repository: RepositoryData,
-}
-
-/// Represents data taken from github webhook
+/// Represents the query string of endpoints which report on a single branch
 #[derive(Debug, Deserialize)]
-struct RepositoryData {
-    name: String,
+pub struct BranchQuery {
+    branch: Option<String>,
 }
 
 /// Represents data to be provided through 'get_project_information'
 #[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ProjectData {
     project: String,
+    default_branch: String,
+    /// Builds of the default branch
     builds: Vec<BuildData>,
+    branches: Vec<BranchData>,
+    /// Latest builds of pull requests, which aren't counted as builds of any branch
+    pull_requests: Vec<BuildData>,
+    /// Latest builds of tags, which aren't counted as builds of any branch either
+    tags: Vec<BuildData>,
 }
 
-/// Represents stored build data
+/// Represents the builds of a single branch of a project
 #[derive(Debug, Serialize)]
+pub struct BranchData {
+    branch: String,
+    builds: Vec<BuildData>,
+}
+
+/// Represents stored build data
+#[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BuildData {
     build_number: i32,
-    build_status: String,
+    build_status: BuildStatus,
+    commit_sha: Option<String>,
+    pr_number: Option<i32>,
+    tag: Option<String>,
+    author: Option<String>,
     archived_files: Vec<String>,
+    steps: Vec<StepData>,
+}
+
+/// Represents the stored result of a single build step
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StepData {
+    index: i32,
+    command: String,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+    started_at: NaiveDateTime,
+    finished_at: NaiveDateTime,
+    duration_ms: i64,
 }
 
 /// Represents project build configuration (.drovah)
@@ -73,12 +124,16 @@ struct CIConfig {
     build: BuildConfig,
     archive: Option<ArchiveConfig>,
     postarchive: Option<PostArchiveConfig>,
+    cleanup: Option<CleanupConfig>,
+    workspace: Option<WorkspaceConfig>,
+    triggers: Option<TriggerConfig>,
 }
 
 /// Represents the build section of .drovah
 #[derive(Debug, Deserialize)]
 struct BuildConfig {
-    commands: Vec<String>,
+    commands: Vec<StepConfig>,
+    timeout: Option<u64>,
 }
 
 /// Represents the archive section of .drovah
@@ -91,153 +146,333 @@ struct ArchiveConfig {
 /// Represents the post archival section of .drovah
 #[derive(Debug, Deserialize)]
 struct PostArchiveConfig {
-    commands: Vec<String>,
+    commands: Vec<StepConfig>,
+}
+
+/// Represents the cleanup section of .drovah
+#[derive(Debug, Deserialize)]
+struct CleanupConfig {
+    commands: Vec<StepConfig>,
+}
+
+/// Represents the workspace section of .drovah
+#[derive(Debug, Default, Deserialize)]
+struct WorkspaceConfig {
+    /// Whether to build in a fresh workspace rather than the project's clone, defaults to true
+    isolated: Option<bool>,
+    #[serde(default)]
+    cleanup: WorkspaceCleanup,
 }
 
 /// Method to run a build for a project
-/// Takes the project name (String) and ref to database (&Database) to store result
-fn run_build(project: String, database: &MysqlConnection) -> Result<(), Box<dyn Error>> {
+/// Takes the project name (String), the queued build and the pool (&DbPool) to store results with
+/// Output of every step is appended to 'build.log' in the build's archive directory
+/// Unless turned off in .drovah, the build runs in its own workspace checked out from the project
+/// Setting 'cancelled' stops the build, only running the cleanup steps
+fn run_build(
+    project: String,
+    queued_build: &Build,
+    cancelled: &AtomicBool,
+    pool: &DbPool,
+) -> Result<(), Box<dyn Error>> {
+    let build_number = queued_build.build_number;
     println!("Building '{}'", project);
     let project_path = format!("data/projects/{}", project);
     let path = Path::new(&project_path);
 
-    let project_id = get_project_id(database, &project);
-
     if path.exists() && path.is_dir() {
-        let settings_file_path = format!("{}/.drovah", project_path);
-        let ci_settings_file = Path::new(&settings_file_path);
-        let settings_string = fs::read_to_string(ci_settings_file)?;
-        let ci_config: CIConfig = toml::from_str(&settings_string)?;
+        let archive_dir = archive_dir(&project, build_number, queued_build.pr_number);
+        let log_path = format!("{}/build.log", archive_dir);
+        let log = BuildLog::create(Path::new(&log_path))?;
+        let noted = |context: &str, e: String| {
+            log.note(&format!("{}: {}", context, e));
+            e
+        };
 
-        if run_commands(
-            ci_config.build.commands,
-            &project_path,
-            ci_config.archive.is_some(),
-        ) {
-            println!("Success! '{}' has been built.", project);
+        let git_ref = match (queued_build.pr_number, &queued_build.tag) {
+            (Some(number), _) => GitRef::PullRequest(number),
+            (None, Some(tag)) => GitRef::Tag(tag.to_owned()),
+            (None, None) => GitRef::Branch(queued_build.branch.to_owned()),
+        };
+        let commit = fetch_commit(&project_path, &git_ref, queued_build.commit_sha.as_deref())
+            .map_err(|e| noted("Failed to fetch commit", e))?;
+        log.note(&format!("Building commit {}", commit));
+        with_connection(pool, |database| {
+            save_build_commit(queued_build.build_id, &commit, database)
+        });
 
-            if let Some(files) = ci_config.archive {
-                if archive_files(
-                    files.files,
-                    project_id.unwrap(),
+        let ci_config = read_ci_config(&project_path, &commit)
+            .map_err(|e| noted("Failed to read .drovah", e))?;
+
+        // Pull requests are built whenever they're opened or updated, whatever the triggers say
+        let skipped = queued_build.pr_number.is_none()
+            && ci_config.triggers.as_ref().is_some_and(|triggers| {
+                !is_triggered(&project_path, triggers, &git_ref, queued_build, &commit)
+            });
+        if skipped {
+            println!("Skipped build {} of '{}'", build_number, project);
+            log.note("Push doesn't match the triggers in .drovah, skipped");
+            with_connection(pool, |database| {
+                let files = vec!["build.log".to_owned()];
+                save_project_build_data(
+                    queued_build.build_id,
+                    BuildStatus::Skipped,
                     database,
-                    files.append_buildnumber,
-                ) {
-                    println!("Successfully archived files for '{}'", project);
-
-                    if let Some(post_archive) = ci_config.postarchive {
-                        if run_commands(post_archive.commands, &project_path, false) {
-                            println!("Successfully ran post-archive commands for '{}'", project);
-                        } else {
-                            println!(
-                                "Error occurred running post-archive commands for '{}'",
-                                project
-                            );
+                    files,
+                    &[],
+                )
+            });
+            return Ok(());
+        }
+
+        let pull_request = queued_build.pr_number.is_some();
+        let workspace_config = ci_config.workspace.unwrap_or_default();
+        // Pull requests are never built in the project's clone, whatever .drovah says
+        let isolated = workspace_config.isolated.unwrap_or(true) || pull_request;
+        let workspace = if isolated {
+            Workspace::create(&project_path, &project, build_number, &commit)
+        } else {
+            Workspace::shared(&project_path, &commit)
+        }
+        .map_err(|e| noted("Failed to create workspace", e))?;
+
+        let deadline = ci_config
+            .build
+            .timeout
+            .or_else(default_build_timeout)
+            .filter(|timeout| *timeout > 0)
+            .map(|timeout| Instant::now() + Duration::from_secs(timeout));
+
+        let mut steps = vec![];
+        let mut files = vec!["build.log".to_owned()];
+
+        let status = match run_commands(
+            ci_config.build.commands,
+            workspace.path(),
+            Some(&log),
+            deadline,
+            cancelled,
+            pull_request,
+            &mut steps,
+        ) {
+            CommandsOutcome::Passed => {
+                println!("Success! '{}' has been built.", project);
+
+                if let Some(archive) = ci_config.archive {
+                    if let Some(archived_files) = archive_files(
+                        archive.files,
+                        workspace.path(),
+                        &archive_dir,
+                        build_number,
+                        archive.append_buildnumber,
+                    ) {
+                        println!("Successfully archived files for '{}'", project);
+                        files.extend(archived_files);
+
+                        // Post-archive commands deploy what was built, which pull requests mustn't do
+                        let post_archive = ci_config
+                            .postarchive
+                            .filter(|_| queued_build.pr_number.is_none());
+                        if let Some(post_archive) = post_archive {
+                            if run_commands(
+                                post_archive.commands,
+                                workspace.path(),
+                                Some(&log),
+                                deadline,
+                                cancelled,
+                                pull_request,
+                                &mut steps,
+                            ) == CommandsOutcome::Passed
+                            {
+                                println!(
+                                    "Successfully ran post-archive commands for '{}'",
+                                    project
+                                );
+                            } else {
+                                println!(
+                                    "Error occurred running post-archive commands for '{}'",
+                                    project
+                                );
+                            }
                         }
+
+                        BuildStatus::Passing
+                    } else {
+                        println!("Failed to archive files for '{}'", project);
+                        log.note("Failed to archive files");
+                        BuildStatus::Failing
                     }
                 } else {
-                    println!("Failed to archive files for '{}'", project);
+                    BuildStatus::Passing
                 }
-            } else {
-                save_project_build_data(project, "passing".to_owned(), database, vec![]);
             }
-        } else {
-            println!("'{}' has failed to build.", project);
-            save_project_build_data(project, "failing".to_owned(), database, vec![]);
+            CommandsOutcome::Failed => {
+                println!("'{}' has failed to build.", project);
+                BuildStatus::Failing
+            }
+            CommandsOutcome::TimedOut => {
+                println!("'{}' has timed out.", project);
+                BuildStatus::TimedOut
+            }
+            CommandsOutcome::Cancelled => {
+                println!(
+                    "Build {} of '{}' has been cancelled.",
+                    build_number, project
+                );
+                log.note("Build cancelled");
+                BuildStatus::Cancelled
+            }
+        };
+
+        // Cleanup always runs, and can't be cancelled
+        if let Some(cleanup) = ci_config.cleanup {
+            if run_commands(
+                cleanup.commands,
+                workspace.path(),
+                Some(&log),
+                None,
+                &AtomicBool::new(false),
+                pull_request,
+                &mut steps,
+            ) != CommandsOutcome::Passed
+            {
+                println!("Error occurred running cleanup commands for '{}'", project);
+            }
+        }
+
+        with_connection(pool, |database| {
+            save_project_build_data(queued_build.build_id, status, database, files, &steps)
+        });
+
+        if workspace_config.cleanup.should_remove(status) {
+            workspace.remove();
         }
+    } else {
+        return Err(format!("'{}' is not a directory", project_path).into());
     }
     Ok(())
 }
 
+/// Reads a project's .drovah as of the given commit, rather than whatever is checked out
+fn read_ci_config(project_path: &str, commit: &str) -> Result<CIConfig, String> {
+    let settings_string = read_file_at(project_path, commit, ".drovah")?;
+    toml::from_str(&settings_string).map_err(|e| e.to_string())
+}
+
+/// Whether the [triggers] of .drovah, as of the commit being built, allow a pushed build to run
+/// Paths are compared against the files the push changed, or failing that, what changed since the ref's previous head
+fn is_triggered(
+    project_path: &str,
+    triggers: &TriggerConfig,
+    git_ref: &GitRef,
+    queued_build: &Build,
+    commit: &str,
+) -> bool {
+    if !triggers.allows(git_ref) {
+        return false;
+    }
+
+    if !triggers.filters_paths() {
+        return true;
+    }
+
+    let listed = queued_build
+        .changed_files
+        .as_deref()
+        .and_then(|changed| serde_json::from_str::<Vec<String>>(changed).ok());
+
+    let changed = match (listed, queued_build.before_sha.as_deref()) {
+        (Some(changed), _) => Ok(changed),
+        (None, Some(before)) if !is_null_sha(before) => changed_files(project_path, before, commit),
+        // New refs have nothing to compare against
+        (None, _) => return true,
+    };
+
+    match changed {
+        Ok(changed) => triggers.allows_paths(&changed),
+        Err(e) => {
+            eprintln!("Error listing changed files in {}: {}", project_path, e);
+            true
+        }
+    }
+}
+
+/// Server-wide default build timeout in seconds, taken from BUILD_TIMEOUT
+/// Defaults to an hour, 0 means builds may run forever
+fn default_build_timeout() -> Option<u64> {
+    match env::var("BUILD_TIMEOUT") {
+        Ok(timeout) => match timeout.parse() {
+            Ok(timeout) => Some(timeout),
+            Err(e) => {
+                eprintln!("Invalid BUILD_TIMEOUT '{}': {}", timeout, e);
+                Some(3600)
+            }
+        },
+        Err(_) => Some(3600),
+    }
+}
+
+/// Where the log and archived files of a build are kept, 'data/archive/<project>/<build number>'
+/// Pull request builds are kept apart, in 'data/archive/<project>/pull-requests/<build number>'
+pub(crate) fn archive_dir(project: &str, build_number: i32, pr_number: Option<i32>) -> String {
+    match pr_number {
+        Some(_) => format!("data/archive/{}/pull-requests/{}", project, build_number),
+        None => format!("data/archive/{}/{}", project, build_number),
+    }
+}
+
 /// Archives nominated files for a project
-/// Files are stored in 'data/archive/<project>/<build number>/
+/// Files are stored in the build's archive directory
+/// Returns the names of the archived files, if any were archived
 fn archive_files(
     files_to_archive: Vec<String>,
-    project_id: i32,
-    database: &MysqlConnection,
+    directory: &str,
+    archive_dir: &str,
+    build_number: i32,
     append_buildnumber: Option<bool>,
-) -> bool {
-    let project_name = get_project_name(&database, project_id);
+) -> Option<Vec<String>> {
     let mut success = false;
+    let mut filenames = vec![];
 
-    if let Some(project_name) = project_name {
-        let archive_folder = format!("data/archive/{}/", project_name);
-        let archive_path = Path::new(&archive_folder);
-        if !archive_path.exists() {
-            if let Err(e) = fs::create_dir_all(archive_path) {
-                eprintln!("Error creating directories: {}, {}", archive_folder, e);
-            }
-        }
-
-        let build_number = get_build_number(&database, project_id) + 1;
+    for file_to_match in files_to_archive {
+        let path_to_search = format!("{}/{}", directory, file_to_match);
+        if let Some(matched) = match_filename_to_file(&path_to_search) {
+            let matched_file_name = matched.split('/').next_back().unwrap();
 
-        let mut filenames = vec![];
+            if append_buildnumber.is_some() {
+                if append_buildnumber.unwrap() {
+                    let ext = Path::new(matched_file_name)
+                        .extension()
+                        .and_then(OsStr::to_str)
+                        .unwrap();
 
-        // Copy log file
-        let from = format!("data/projects/{}/build.log", project_name);
-        let to = format!("data/archive/{}/{}/build.log", project_name, build_number);
-        if copy(&from, &to) {
-            filenames.push("build.log".to_owned());
-            if let Err(e) = fs::remove_file(Path::new(&from)) {
-                eprintln!(
-                    "Error when deleting build.log for project: {}, {}",
-                    project_name, e
-                );
-            }
-        } else {
-            println!(
-                "Error copying build.log for {} with build number: {}",
-                project_name, build_number
-            );
-        }
+                    let replace = format!(".{}", ext);
+                    let filename = matched_file_name.replace(&replace, "");
+                    let final_file = format!("{}-b{}.{}", filename, build_number, ext);
 
-        // Copy other files
-        for file_to_match in files_to_archive {
-            let path_to_search = format!("data/projects/{}/{}", project_name, file_to_match);
-            if let Some(matched) = match_filename_to_file(&path_to_search) {
-                let matched_file_name = matched.split('/').last().unwrap();
-
-                if append_buildnumber.is_some() {
-                    if append_buildnumber.unwrap() {
-                        let ext = Path::new(matched_file_name)
-                            .extension()
-                            .and_then(OsStr::to_str)
-                            .unwrap();
-
-                        let replace = format!(".{}", ext);
-                        let filename = matched_file_name.replace(&replace, "");
-                        let final_file = format!("{}-b{}.{}", filename, build_number, ext);
-
-                        let to = format!(
-                            "data/archive/{}/{}/{}",
-                            project_name, build_number, final_file
-                        );
-
-                        if copy(&matched, &to) {
-                            filenames.push(final_file.to_owned());
-                            success = true;
-                        }
-                    }
-                } else {
-                    let to = format!(
-                        "data/archive/{}/{}/{}",
-                        project_name, build_number, matched_file_name
-                    );
+                    let to = format!("{}/{}", archive_dir, final_file);
 
                     if copy(&matched, &to) {
-                        filenames.push(matched_file_name.to_owned());
+                        filenames.push(final_file.to_owned());
                         success = true;
                     }
                 }
+            } else {
+                let to = format!("{}/{}", archive_dir, matched_file_name);
+
+                if copy(&matched, &to) {
+                    filenames.push(matched_file_name.to_owned());
+                    success = true;
+                }
             }
         }
+    }
 
-        if success {
-            save_project_build_data(project_name, "passing".to_owned(), database, filenames);
-        }
+    if success {
+        return Some(filenames);
     }
 
-    success
+    None
 }
 
 /// Launches the actix webserver
@@ -248,14 +483,19 @@ pub async fn launch_webserver() -> io::Result<()> {
 
     let bind_address = env::var("BIND_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8000".to_owned());
 
-    HttpServer::new(move || {
-        let db_url =
-            env::var("DATABASE_URL").expect("No DATABASE_URL environment variable defined!");
-        let manager = ConnectionManager::<MysqlConnection>::new(db_url);
-        let pool = r2d2::Pool::builder()
-            .build(manager)
-            .expect("Failed to create pool.");
+    let db_url = env::var("DATABASE_URL").expect("No DATABASE_URL environment variable defined!");
+    let manager = ConnectionManager::<MysqlConnection>::new(db_url);
+    let pool = r2d2::Pool::builder()
+        .build(manager)
+        .expect("Failed to create pool.");
+
+    // Shared between every worker, so builds are queued in one place
+    let queue = BuildQueue::start(pool.clone());
+    queue.recover();
+    poller::start(pool.clone(), queue.clone());
+    let queue = web::Data::from(queue);
 
+    HttpServer::new(move || {
         let allowed_origin =
             env::var("ALLOWED_ORIGIN").expect("No ALLOWED_ORIGIN environment variable set!");
 
@@ -265,191 +505,494 @@ pub async fn launch_webserver() -> io::Result<()> {
 
         // Create app
         App::new()
-            .data(pool)
+            .data(pool.clone())
+            .app_data(queue.clone())
             .wrap(cors)
             .wrap(middleware::Logger::default())
             .service(get_latest_status_badge)
             .service(get_latest_file)
             .service(get_status_badge_for_build)
             .service(get_file_for_build)
+            .service(get_file_for_tag)
             .service(get_project_information)
+            .service(stream_build_log)
+            .service(cancel_build)
             .service(github_webhook)
-            .wrap(Logger::default())
+            .service(gitlab_webhook)
+            .service(gitea_webhook)
+            .service(bitbucket_webhook)
+            .service(trigger_build)
     })
     .bind(bind_address)?
     .run()
     .await
 }
 
-/// Saves project build data to database
+/// Saves project build data, along with the results of each step, to database
 fn save_project_build_data(
-    project: String,
-    status: String,
+    build_id: i32,
+    status: BuildStatus,
     database: &MysqlConnection,
     archived_files: Vec<String>,
+    steps: &[StepResult],
 ) {
-    let p_id = get_project_id(&database, &project);
-    if let Some(p_id) = p_id {
-        let build_num = get_build_number(&database, p_id) + 1;
-
-        let sep_files = archived_files.join(", ");
-
-        if let Err(why) = insert_into(build::builds)
-            .values((
-                build::project_id.eq(p_id),
-                build::build_number.eq(build_num),
-                build::branch.eq("master".to_owned()),
-                build::files.eq(sep_files),
-                build::status.eq(status),
-            ))
-            .execute(database)
-        {
-            eprintln!(
-                "Error on insert of build {} for {}! {}",
-                build_num, project, why
+    let sep_files = archived_files.join(", ");
+
+    if let Err(why) = update(build::builds.find(build_id))
+        .set((build::files.eq(sep_files), build::status.eq(status)))
+        .execute(database)
+    {
+        eprintln!("Error on update of build {}! {}", build_id, why);
+    }
+
+    save_build_steps(build_id, steps, database);
+}
+
+/// Saves the commit a build is building to database, as soon as it is known
+fn save_build_commit(build_id: i32, commit: &str, database: &MysqlConnection) {
+    if let Err(why) = update(build::builds.find(build_id))
+        .set(build::commit_sha.eq(commit))
+        .execute(database)
+    {
+        eprintln!("Error saving commit of build {}! {}", build_id, why);
+    }
+}
+
+/// Saves the results of each step of a build to database
+fn save_build_steps(build_id: i32, steps: &[StepResult], database: &MysqlConnection) {
+    let rows = steps
+        .iter()
+        .enumerate()
+        .map(|(index, step)| {
+            (
+                step::build_id.eq(build_id),
+                step::step_index.eq(index as i32),
+                step::command.eq(&step.command),
+                step::exit_code.eq(step.exit_code),
+                step::exit_signal.eq(step.signal),
+                step::started_at.eq(step.started_at),
+                step::finished_at.eq(step.finished_at),
+                step::duration_ms.eq(step.duration_ms),
             )
-        }
+        })
+        .collect::<Vec<_>>();
+
+    if let Err(why) = insert_into(step::build_steps)
+        .values(&rows)
+        .execute(database)
+    {
+        eprintln!("Error on insert of steps for build {}! {}", build_id, why);
     }
 }
 
-/// Verifies the auth header for the commit via webhook
+/// Verifies the signature of a webhook delivery against the secret, if one is configured
+/// Deliveries without a signature are rejected, unless WEBHOOK_SIGNATURE_REQUIRED is set to false
 pub fn verify_authentication_header(
     headers: &HashMap<String, String>,
     body: &[u8],
+    secret: Option<String>,
+) -> Result<(), HttpResponse> {
+    verify_signature(get_signature_header(headers)?, body, secret)
+}
+
+/// Verifies a webhook delivery signed with a bare hex hmac sha256 in the given header, as Gitea's are
+/// Deliveries without the header are rejected, unless WEBHOOK_SIGNATURE_REQUIRED is set to false
+pub fn verify_sha256_header(
+    headers: &HashMap<String, String>,
+    header: &str,
+    body: &[u8],
+    secret: Option<String>,
+) -> Result<(), HttpResponse> {
+    let signature = headers
+        .get(header)
+        .map(|signature| Signature::Sha256(signature.clone()));
+
+    verify_signature(signature, body, secret)
+}
+
+/// Verifies a signature against the secret, if one is configured
+fn verify_signature(
+    signature: Option<Signature>,
+    body: &[u8],
+    secret: Option<String>,
+) -> Result<(), HttpResponse> {
+    match (signature, secret) {
+        (Some(signature), Some(secret)) => verify_signature_header(signature, secret, body),
+        (Some(_), None) => {
+            println!("warning: Got signed request, but no secret is configured to verify it");
+            Err(HttpResponse::Unauthorized().body("No secret configured"))
+        }
+        (None, _) if signature_required() => {
+            println!("warning: Got request without a signature");
+            Err(HttpResponse::Unauthorized().body("Missing signature"))
+        }
+        (None, _) => Ok(()),
+    }
+}
+
+/// Verifies a webhook delivery which carries the secret itself in a header, as GitLab's do
+/// Deliveries without the header are rejected, unless WEBHOOK_SIGNATURE_REQUIRED is set to false
+pub fn verify_token_header(
+    headers: &HashMap<String, String>,
+    header: &str,
+    secret: Option<String>,
 ) -> Result<(), HttpResponse> {
-    let secret = env::var("GITHUB_SECRET").expect("No GITHUB_SECRET environment variable!");
-    let mut signature_valid = false;
+    match (headers.get(header), secret) {
+        (Some(token), Some(secret)) if constant_time_eq(token.as_bytes(), secret.as_bytes()) => {
+            Ok(())
+        }
+        (Some(_), Some(_)) => {
+            println!("Got wrong token in {}", header);
+            Err(HttpResponse::Unauthorized().body("Invalid token"))
+        }
+        (Some(_), None) => {
+            println!("warning: Got request with a token, but no secret is configured to check it");
+            Err(HttpResponse::Unauthorized().body("No secret configured"))
+        }
+        (None, _) if signature_required() => {
+            println!("warning: Got request without a token");
+            Err(HttpResponse::Unauthorized().body("Missing token"))
+        }
+        (None, _) => Ok(()),
+    }
+}
+
+/// Gets the token given in an 'Authorization: Bearer <token>' header, if there is one
+pub(crate) fn get_bearer_token(headers: &HashMap<String, String>) -> Option<&str> {
+    let (scheme, token) = headers.get("authorization")?.split_once(' ')?;
 
-    // Check for a correct signature, if we have as secret or both authentication methods are required
-    let signature = get_signature_header(headers)?;
-    if !signature.is_empty() {
-        verify_signature_header(signature, secret, body)?;
-        signature_valid = true;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
     }
+}
 
-    if signature_valid {
-        return Ok(());
+/// Compares two secrets without giving away how much of them matched through timing
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Whether webhook deliveries must be signed, taken from WEBHOOK_SIGNATURE_REQUIRED
+/// Defaults to true, only turning it off explicitly allows unsigned deliveries
+fn signature_required() -> bool {
+    match env::var("WEBHOOK_SIGNATURE_REQUIRED") {
+        Ok(required) => match required.parse() {
+            Ok(required) => required,
+            Err(e) => {
+                eprintln!("Invalid WEBHOOK_SIGNATURE_REQUIRED '{}': {}", required, e);
+                true
+            }
+        },
+        Err(_) => true,
     }
+}
 
-    Ok(())
+/// A signature from a webhook header, as hex, by the hash it was made with
+#[derive(Debug, PartialEq)]
+enum Signature {
+    Sha1(String),
+    Sha256(String),
 }
 
 /// Credit - https://github.com/Nukesor/webhook-server/blob/master/src/authentication.rs#L88
-/// Verify the signature header. Checks our own signature generated by hmac sha256 with secret and payload
+/// Verify the signature header. Checks our own signature generated by hmac with secret and payload
 /// against the signature provided in the header.
 fn verify_signature_header(
-    signature: String,
+    signature: Signature,
     secret: String,
     body: &[u8],
 ) -> Result<(), HttpResponse> {
-    // Try to decode the sha256 into bytes. Should be a valid hex string
-    let signature_bytes = match hex::decode(&signature) {
+    let valid = match &signature {
+        Signature::Sha1(hex) => verify_hmac::<Hmac<Sha1>>(&secret, body, hex),
+        Signature::Sha256(hex) => verify_hmac::<Hmac<Sha256>>(&secret, body, hex),
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        println!("Got wrong signature: {:?}", signature);
+        Err(HttpResponse::Unauthorized().body("Invalid signature"))
+    }
+}
+
+/// Whether a hex signature matches the hmac of the body with the secret
+/// The comparison is done by the hmac itself, in constant time
+fn verify_hmac<M: Mac + NewMac>(secret: &str, body: &[u8], signature: &str) -> bool {
+    // Should be a valid hex string
+    let signature_bytes = match hex::decode(signature) {
         Ok(result) => result,
         Err(error) => {
             println!("Error decoding signature: {}, {}", signature, error);
-            return Err(HttpResponse::Unauthorized().body("Invalid sha256 signature"));
+            return false;
         }
     };
 
-    // Generate the own hmac sha256 from the secret and body and verify that it's identical to the signature
-    let secret_bytes = secret.into_bytes();
-    let expected_signature = generate_signature_sha256(&secret_bytes, body);
-
-    match expected_signature.clone().verify(&signature_bytes) {
-        Ok(()) => Ok(()),
-        Err(_) => {
-            println!(
-                "Our sha256: {}",
-                hex::encode(expected_signature.finalize().into_bytes())
-            );
-            println!("Got wrong sha256: {}", signature);
-            Err(HttpResponse::Unauthorized().body("Invalid sha256 signature"))
-        }
-    }
-}
-
-/// Create a hmac SHA256 instance from a secret and body
-fn generate_signature_sha256(secret_bytes: &[u8], body: &[u8]) -> HmacSha256 {
     let mut hmac =
-        HmacSha256::new_from_slice(secret_bytes).expect("Couldn't create hmac with current secret");
+        M::new_from_slice(secret.as_bytes()).expect("Couldn't create hmac with current secret");
     hmac.update(body);
-    hmac
+    hmac.verify(&signature_bytes).is_ok()
 }
 
 /// Credit - https://github.com/Nukesor/webhook-server/blob/master/src/authentication.rs#L63
 /// Extract the correct signature header content from all headers
 /// It's possible to receive the signature from multiple Headers, since Github uses their own
-/// Header name for their signature method.
-fn get_signature_header(headers: &HashMap<String, String>) -> Result<String, HttpResponse> {
-    let mut header = headers.get("signature");
-    if header.is_none() {
-        header = headers.get("x-hub-signature-256");
-    }
-
-    // We dont' find any headers for signatures and this method is not required
-    let mut header = if let Some(header) = header {
-        header.clone()
-    } else {
-        return Ok("".to_string());
+/// Header names for their signature methods. Sha256 signatures are preferred when both are sent.
+/// The hash is taken from the prefix, as Bitbucket sends sha256 signatures in GitHub's legacy header.
+fn get_signature_header(
+    headers: &HashMap<String, String>,
+) -> Result<Option<Signature>, HttpResponse> {
+    let header = match headers
+        .get("signature")
+        .or_else(|| headers.get("x-hub-signature-256"))
+        .or_else(|| headers.get("x-hub-signature"))
+    {
+        Some(header) => header,
+        None => return Ok(None),
     };
 
-    // Header must be formatted like this: sha256={{hash}}
-    if !header.starts_with("sha256=") {
-        println!("warning: Got request with missing sha256= prefix");
+    // Header must be formatted like this: sha256={{hash}}, or sha1={{hash}} for legacy signatures
+    if let Some(signature) = header.strip_prefix("sha256=") {
+        Ok(Some(Signature::Sha256(signature.to_owned())))
+    } else if let Some(signature) = header.strip_prefix("sha1=") {
+        Ok(Some(Signature::Sha1(signature.to_owned())))
+    } else {
+        println!("warning: Got request with missing signature prefix");
         Err(HttpResponse::Unauthorized()
             .body("Error while parsing signature: Couldn't find prefix"))
-    } else {
-        Ok(header.split_off(5))
     }
 }
 
 /// Runs the commands required for the build in .drovah
-fn run_commands(commands: Vec<String>, directory: &str, save_log: bool) -> bool {
-    let mut success = 0;
-
-    let commands_len = commands.len();
-
-    for command in commands {
-        let split: Vec<&str> = command.split(' ').collect();
-
-        let program = split.first().expect("Error, commands are formatted wrong!");
-        let process;
-
-        if save_log {
-            let output_path = format!("{}/build.log", directory);
-            let outputs = File::create(&output_path).expect("Error creating file 'build.log'");
-            let errors = outputs.try_clone().unwrap();
-
-            process = Command::new(program)
-                .current_dir(directory)
-                .args(&split[1..])
-                .stdout(Stdio::from(outputs))
-                .stderr(Stdio::from(errors))
-                .spawn()
-                .expect(
-                    "run_commands failed, are they formatted correctly? is the program installed?",
-                );
-        } else {
-            process = Command::new(program)
-                .current_dir(directory)
-                .args(&split[1..])
-                .stdout(Stdio::piped())
-                .spawn()
-                .expect(
-                    "run_commands failed, are they formatted correctly? is the program installed?",
-                );
+/// Output of each command is appended to the build log, if one is given
+/// The result of each command is pushed onto 'results'
+/// Once the deadline passes or the build is cancelled, the running command is killed and no more commands are run
+/// With 'clean_env', as for pull requests, the commands get little of drovah's environment
+fn run_commands(
+    commands: Vec<StepConfig>,
+    directory: &str,
+    log: Option<&BuildLog>,
+    deadline: Option<Instant>,
+    cancelled: &AtomicBool,
+    clean_env: bool,
+    results: &mut Vec<StepResult>,
+) -> CommandsOutcome {
+    let mut outcome = CommandsOutcome::Passed;
+
+    for step in commands {
+        if cancelled.load(Ordering::SeqCst) {
+            return CommandsOutcome::Cancelled;
         }
 
-        let result = process
-            .wait_with_output()
-            .expect("Unexpectedly died on commands!");
+        let step_number = results.len() + 1;
+        let mut result = StepResult::start(step.line());
 
-        if result.status.success() {
-            success += 1;
+        if let Some(log) = log {
+            log.step_started(step_number, &result);
         }
+
+        let step_deadline = match step.timeout() {
+            Some(timeout) => {
+                let step_deadline = Instant::now() + Duration::from_secs(timeout);
+                Some(deadline.map_or(step_deadline, |deadline| deadline.min(step_deadline)))
+            }
+            None => deadline,
+        };
+
+        let ran = run_step(&step, directory, log, step_deadline, cancelled, clean_env);
+        let (status, stopped) = match ran {
+            Ok(StepExit::Exited(status)) => (Some(status), None),
+            Ok(StepExit::TimedOut(status)) => (Some(status), Some(CommandsOutcome::TimedOut)),
+            Ok(StepExit::Cancelled(status)) => (Some(status), Some(CommandsOutcome::Cancelled)),
+            Err(e) => {
+                eprintln!("Error running '{}': {}", step.line(), e);
+                if let Some(log) = log {
+                    log.note(&e);
+                }
+                (None, None)
+            }
+        };
+
+        result = result.finish(status);
+        if let Some(log) = log {
+            if stopped == Some(CommandsOutcome::TimedOut) {
+                log.note(&format!("Step {} timed out", step_number));
+            }
+            log.step_finished(step_number, &result);
+        }
+
+        let success = result.success();
+        results.push(result);
+
+        if let Some(stopped) = stopped {
+            return stopped;
+        }
+
+        if !success {
+            outcome = CommandsOutcome::Failed;
+        }
+    }
+
+    outcome
+}
+
+/// Runs a single step to completion, returning how it exited
+/// If the deadline passes or the build is cancelled first, the step and everything it started is killed
+fn run_step(
+    step: &StepConfig,
+    directory: &str,
+    log: Option<&BuildLog>,
+    deadline: Option<Instant>,
+    cancelled: &AtomicBool,
+    clean_env: bool,
+) -> Result<StepExit, String> {
+    let mut command = build_command(step, directory, clean_env)?;
+
+    if let Some(log) = log {
+        let (outputs, errors) = log
+            .stdio()
+            .map_err(|e| format!("Error opening build log: {}", e))?;
+
+        command.stdout(outputs).stderr(errors);
+    } else {
+        command.stdout(Stdio::null()).stderr(Stdio::null());
     }
 
-    success as usize == commands_len
+    let mut process = command
+        .spawn()
+        .map_err(|e| format!("Error starting process, is the program installed? {}", e))?;
+
+    wait_for_step(&mut process, deadline, cancelled)
+        .map_err(|e| format!("Error waiting for process: {}", e))
+}
+
+/// Follows the log of a build as a stream of Server-Sent Events, checking for new output every half second
+/// Once the build has been saved, any remaining output is sent followed by a 'status' event
+/// The stream ends early if the build doesn't exist, and is dropped if the receiving client goes away
+pub(crate) fn tail_build_log(
+    log_path: String,
+    pool: DbPool,
+    project_id: i32,
+    build_number: i32,
+) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    let tail = LogTail {
+        log_path,
+        log: None,
+        pending: vec![],
+        idle_polls: 0,
+    };
+    let interval = time::interval(Duration::from_millis(500));
+
+    stream::unfold(Some((tail, interval)), move |state| {
+        let pool = pool.clone();
+
+        async move {
+            let (mut tail, mut interval) = state?;
+
+            loop {
+                interval.tick().await;
+
+                let pool = pool.clone();
+                let (followed, events, done) = web::block(move || {
+                    let (events, done) = tail.follow(&pool, project_id, build_number);
+                    Ok::<_, ()>((tail, events, done))
+                })
+                .await
+                .ok()?;
+                tail = followed;
+
+                if done && events.is_empty() {
+                    return None;
+                }
+
+                if !events.is_empty() {
+                    let next = Some((tail, interval)).filter(|_| !done);
+                    return Some((Ok(Bytes::from(events)), next));
+                }
+            }
+        }
+    })
+}
+
+/// A build log being followed by 'tail_build_log'
+struct LogTail {
+    log_path: String,
+    log: Option<File>,
+    /// Output read since the last complete line
+    pending: Vec<u8>,
+    idle_polls: u32,
+}
+
+impl LogTail {
+    /// Reads any output written since it was last called, returning it as events along with whether that's the end
+    /// Following ends once the build has been saved, or if there's no build to follow
+    fn follow(&mut self, pool: &DbPool, project_id: i32, build_number: i32) -> (String, bool) {
+        // Check for completion before reading, so no output written before the build was saved is missed
+        let finished = match pool.get() {
+            Ok(database) => match get_build(&database, project_id, build_number) {
+                Some(build) => Some(build).filter(|build| build.status.is_finished()),
+                None => return (String::new(), true),
+            },
+            Err(e) => {
+                eprintln!("Error getting db connection to stream log: {}", e);
+                None
+            }
+        };
+
+        if self.log.is_none() {
+            self.log = File::open(&self.log_path).ok();
+        }
+
+        let mut read = vec![];
+        if let Some(log) = self.log.as_mut() {
+            if let Err(e) = log.read_to_end(&mut read) {
+                eprintln!("Error reading {}: {}", self.log_path, e);
+            }
+        }
+        self.pending.extend_from_slice(&read);
+
+        let mut events = take_lines(&mut self.pending);
+
+        if let Some(build) = finished {
+            if !self.pending.is_empty() {
+                let rest = String::from_utf8_lossy(&self.pending).into_owned();
+                events.push_str(&format!("data: {}\n\n", rest));
+            }
+
+            events.push_str(&format!("event: status\ndata: {}\n\n", build.status));
+            return (events, true);
+        }
+
+        // Keep the connection alive (and notice if the client has gone) through quiet periods
+        self.idle_polls = if read.is_empty() {
+            self.idle_polls + 1
+        } else {
+            0
+        };
+        if self.idle_polls >= 30 {
+            self.idle_polls = 0;
+            events.push_str(": keep-alive\n\n");
+        }
+
+        (events, false)
+    }
+}
+
+/// Takes every complete line from the start of 'pending', returning them as events
+/// Only complete lines are decoded, so characters split between reads aren't mangled
+fn take_lines(pending: &mut Vec<u8>) -> String {
+    let mut events = String::new();
+
+    while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+        let line: Vec<u8> = pending.drain(..=end).collect();
+        let line = String::from_utf8_lossy(&line);
+        events.push_str(&format!(
+            "data: {}\n\n",
+            line.trim_end_matches(&['\n', '\r'][..])
+        ));
+    }
+
+    events
 }
 
 /// Matches a filename into a file
@@ -463,7 +1006,7 @@ fn match_filename_to_file(filename: &str) -> Option<String> {
     }
 
     // If not, lets look for it
-    let file_to_look_for = filename.split('/').last().unwrap();
+    let file_to_look_for = filename.split('/').next_back().unwrap();
 
     // Find all files starting with
     if let Some(path_parent) = path.parent() {
@@ -472,7 +1015,7 @@ fn match_filename_to_file(filename: &str) -> Option<String> {
                 if file
                     .file_name()
                     .to_string_lossy()
-                    .starts_with(&file_to_look_for)
+                    .starts_with(file_to_look_for)
                 {
                     let path = file.path().to_string_lossy().to_string();
                     return Option::Some(path);
@@ -505,23 +1048,25 @@ fn copy(from_str: &str, to_str: &str) -> bool {
     true
 }
 
-/// Returns status badge for given status
-fn get_project_status_badge(status: String) -> String {
-    let mut badge_options: BadgeOptions = Default::default();
+/// Returns status badge for given status, None meaning the project has never been built
+fn get_project_status_badge(status: Option<BuildStatus>) -> String {
+    let (status, color) = match status {
+        Some(BuildStatus::Queued) => ("queued", "#9f9f9f"),
+        Some(BuildStatus::Running) => ("running", "#007ec6"),
+        Some(BuildStatus::Passing) => ("passing", "#4c1"),
+        Some(BuildStatus::Failing) => ("failing", "#ed2e25"),
+        Some(BuildStatus::Cancelled) => ("cancelled", "#9f9f9f"),
+        Some(BuildStatus::TimedOut) => ("timed out", "#fe7d37"),
+        Some(BuildStatus::Errored) => ("errored", "#ed2e25"),
+        Some(BuildStatus::Skipped) => ("skipped", "#9f9f9f"),
+        None => ("no builds", "#9f9f9f"),
+    };
 
-    if status.eq("passing") {
-        badge_options = BadgeOptions {
-            subject: "drovah".to_owned(),
-            status,
-            color: "#4c1".to_owned(),
-        };
-    } else if status.eq("failing") {
-        badge_options = BadgeOptions {
-            subject: "drovah".to_owned(),
-            status,
-            color: "#ed2e25".to_owned(),
-        };
-    }
+    let badge_options = BadgeOptions {
+        subject: "drovah".to_owned(),
+        status: status.to_owned(),
+        color: color.to_owned(),
+    };
 
     if let Ok(badge) = Badge::new(badge_options) {
         let svg = badge.to_svg();
@@ -553,6 +1098,39 @@ pub fn get_project_name(connection: &MysqlConnection, pid: i32) -> Option<String
     Some(result.first()?.project_name.to_owned())
 }
 
+/// Gets a project by name
+pub fn get_project(connection: &MysqlConnection, project: &str) -> Option<Project> {
+    let result = proj::projects
+        .filter(proj::project_name.eq(project))
+        .limit(1)
+        .load::<Project>(connection)
+        .expect("Error getting project!");
+
+    result.into_iter().next()
+}
+
+/// Gets the webhook secret of a given project, if it has its own
+pub fn get_webhook_secret(connection: &MysqlConnection, project: &str) -> Option<String> {
+    let result = proj::projects
+        .filter(proj::project_name.eq(project))
+        .limit(1)
+        .load::<Project>(connection)
+        .expect("Error getting webhook secret!");
+
+    result.into_iter().next()?.webhook_secret
+}
+
+/// Gets the branch of a given project id which is built and reported on by default
+pub fn get_default_branch(connection: &MysqlConnection, pid: i32) -> Option<String> {
+    let result = proj::projects
+        .filter(proj::project_id.eq(pid))
+        .limit(1)
+        .load::<Project>(connection)
+        .expect("Error getting default branch from id!");
+
+    Some(result.first()?.default_branch.to_owned())
+}
+
 /// Gets the latest build number of a given project
 pub fn get_build_number(connection: &MysqlConnection, pid: i32) -> i32 {
     let result = build::builds
@@ -568,49 +1146,158 @@ pub fn get_build_number(connection: &MysqlConnection, pid: i32) -> i32 {
     }
 }
 
-/// Retrieves the latest build status for a given project
-pub fn get_latest_build_status(connection: &MysqlConnection, pid: i32) -> String {
-    let result = build::builds
+/// Retrieves the latest build of a branch of a given project, if it has been built
+/// Pull request and tag builds are left out, as they aren't builds of the branch, as are skipped builds, which built nothing
+pub fn get_latest_build(connection: &MysqlConnection, pid: i32, branch: &str) -> Option<Build> {
+    let mut result = build::builds
         .filter(build::project_id.eq(pid))
+        .filter(build::branch.eq(branch))
+        .filter(build::pr_number.is_null())
+        .filter(build::tag.is_null())
+        .filter(build::status.ne(BuildStatus::Skipped))
+        .order(build::build_number.desc())
         .limit(1)
         .load::<Build>(connection)
-        .expect("Error getting project name from id!");
+        .expect("Error getting latest build!");
 
-    if let Some(first) = result.first() {
-        first.status.to_string()
-    } else {
-        "failing".to_string()
-    }
+    result.pop()
 }
 
-/// Retrieves the status for a given build number
-pub fn get_status_for_build(connection: &MysqlConnection, pid: i32, build_num: i32) -> String {
-    let result = build::builds
+/// Retrieves the latest passing build of a branch of a given project, whose files can be downloaded
+/// Builds still queued or running, or which didn't pass, have nothing (but maybe a log) archived
+pub fn get_latest_passing_build(
+    connection: &MysqlConnection,
+    pid: i32,
+    branch: &str,
+) -> Option<Build> {
+    let mut result = build::builds
+        .filter(build::project_id.eq(pid))
+        .filter(build::branch.eq(branch))
+        .filter(build::pr_number.is_null())
+        .filter(build::tag.is_null())
+        .filter(build::status.eq(BuildStatus::Passing))
+        .order(build::build_number.desc())
+        .limit(1)
+        .load::<Build>(connection)
+        .expect("Error getting latest passing build!");
+
+    result.pop()
+}
+
+/// Retrieves the latest passing build of a tag of a given project, if it has been built
+pub fn get_tag_build(connection: &MysqlConnection, pid: i32, tag: &str) -> Option<Build> {
+    let mut result = build::builds
+        .filter(build::project_id.eq(pid))
+        .filter(build::tag.eq(tag))
+        .filter(build::status.eq(BuildStatus::Passing))
+        .order(build::build_number.desc())
+        .limit(1)
+        .load::<Build>(connection)
+        .expect("Error getting build of tag!");
+
+    result.pop()
+}
+
+/// Retrieves the status of the latest build of a branch of a given project, if it has been built
+pub fn get_latest_build_status(
+    connection: &MysqlConnection,
+    pid: i32,
+    branch: &str,
+) -> Option<BuildStatus> {
+    Some(get_latest_build(connection, pid, branch)?.status)
+}
+
+/// Retrieves a given build number, if it has been saved
+pub fn get_build(connection: &MysqlConnection, pid: i32, build_num: i32) -> Option<Build> {
+    let mut result = build::builds
         .filter(build::project_id.eq(pid))
         .filter(build::build_number.eq(build_num))
         .limit(1)
         .load::<Build>(connection)
-        .expect("Error getting project name from id!");
+        .expect("Error getting build from number!");
 
-    if let Some(first) = result.first() {
-        first.status.to_string()
-    } else {
-        "failing".to_string()
-    }
+    result.pop()
+}
+
+/// Retrieves the status for a given build number
+pub fn get_status_for_build(
+    connection: &MysqlConnection,
+    pid: i32,
+    build_num: i32,
+) -> Option<BuildStatus> {
+    Some(get_build(connection, pid, build_num)?.status)
 }
 
 /// Retrieves the data of a project in ProjectData format
+/// Each branch is given its latest 10 builds, oldest first
 pub fn get_project_data(connection: &MysqlConnection, pid: i32) -> Option<ProjectData> {
-    let result = build::builds
+    let project_name = get_project_name(connection, pid)?;
+    let default_branch = get_default_branch(connection, pid)?;
+
+    let branch_names = build::builds
+        .filter(build::project_id.eq(pid))
+        .filter(build::pr_number.is_null())
+        .filter(build::tag.is_null())
+        .select(build::branch)
+        .distinct()
+        .order(build::branch.asc())
+        .load::<String>(connection)
+        .expect("Error getting branches of project!");
+
+    let mut branches = vec![];
+    for branch in branch_names {
+        let result = build::builds
+            .filter(build::project_id.eq(pid))
+            .filter(build::branch.eq(&branch))
+            .filter(build::pr_number.is_null())
+            .filter(build::tag.is_null())
+            .order(build::build_number.desc())
+            .limit(10)
+            .load::<Build>(connection)
+            .expect("Error getting builds of branch!");
+
+        branches.push(BranchData {
+            branch,
+            builds: get_builds_data(connection, result),
+        });
+    }
+
+    let pull_requests = build::builds
         .filter(build::project_id.eq(pid))
+        .filter(build::pr_number.is_not_null())
+        .order(build::build_number.desc())
         .limit(10)
         .load::<Build>(connection)
-        .expect("Error getting project name from id!");
+        .expect("Error getting pull request builds!");
 
-    let project_name = get_project_name(connection, pid);
+    let tags = build::builds
+        .filter(build::project_id.eq(pid))
+        .filter(build::tag.is_not_null())
+        .order(build::build_number.desc())
+        .limit(10)
+        .load::<Build>(connection)
+        .expect("Error getting tag builds!");
+
+    let builds = branches
+        .iter()
+        .find(|branch| branch.branch == default_branch)
+        .map(|branch| branch.builds.clone())
+        .unwrap_or_default();
 
+    Some(ProjectData {
+        project: project_name,
+        default_branch,
+        builds,
+        branches,
+        pull_requests: get_builds_data(connection, pull_requests),
+        tags: get_builds_data(connection, tags),
+    })
+}
+
+/// Converts builds, newest first, into BuildData oldest first
+fn get_builds_data(connection: &MysqlConnection, builds: Vec<Build>) -> Vec<BuildData> {
     let mut build_data_vec = vec![];
-    for build in result {
+    for build in builds.into_iter().rev() {
         let split_files = build
             .files
             .split_terminator(", ")
@@ -620,14 +1307,38 @@ pub fn get_project_data(connection: &MysqlConnection, pid: i32) -> Option<Projec
         build_data_vec.push(BuildData {
             build_number: build.build_number,
             build_status: build.status,
+            commit_sha: build.commit_sha,
+            pr_number: build.pr_number,
+            tag: build.tag,
+            author: build.author,
             archived_files: split_files,
+            steps: get_build_steps(connection, build.build_id),
         });
     }
 
-    Some(ProjectData {
-        project: project_name?,
-        builds: build_data_vec,
-    })
+    build_data_vec
+}
+
+/// Retrieves the results of each step for a given build id
+pub fn get_build_steps(connection: &MysqlConnection, bid: i32) -> Vec<StepData> {
+    let result = step::build_steps
+        .filter(step::build_id.eq(bid))
+        .order(step::step_index.asc())
+        .load::<BuildStep>(connection)
+        .expect("Error getting build steps from id!");
+
+    result
+        .into_iter()
+        .map(|step| StepData {
+            index: step.step_index,
+            command: step.command,
+            exit_code: step.exit_code,
+            signal: step.exit_signal,
+            started_at: step.started_at,
+            finished_at: step.finished_at,
+            duration_ms: step.duration_ms,
+        })
+        .collect()
 }
 
 /// Credit - https://github.com/Nukesor/webhook-server/blob/master/src/web.rs#L148
@@ -636,9 +1347,8 @@ pub fn get_headers_hash_map(map: &HeaderMap) -> Result<HashMap<String, String>,
 
     for (key, header_value) in map.iter() {
         let key = key.as_str().to_string();
-        let value: String;
-        match header_value.to_str() {
-            Ok(header_value) => value = header_value.to_string(),
+        let value = match header_value.to_str() {
+            Ok(header_value) => header_value.to_string(),
             Err(error) => {
                 let message = format!("Couldn't parse header: {}", error);
                 println!("{}", message);
@@ -674,4 +1384,97 @@ mod tests {
 
         assert_eq!(formatted, "project-v2.1-b5.zip");
     }
+
+    #[test]
+    fn test_run_commands_appends_to_log() {
+        let dir = env::temp_dir().join(format!("drovah-log-{}", std::process::id()));
+        let log_path = dir.join("build.log");
+        let log = BuildLog::create(&log_path).unwrap();
+
+        let mut steps = vec![];
+        let commands = vec!["echo first".into(), "echo second".into()];
+        let outcome = run_commands(
+            commands,
+            dir.to_str().unwrap(),
+            Some(&log),
+            None,
+            &AtomicBool::new(false),
+            false,
+            &mut steps,
+        );
+        assert_eq!(outcome, CommandsOutcome::Passed);
+
+        let contents = fs::read_to_string(&log_path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(contents.contains("first\n"));
+        assert!(contents.contains("second\n"));
+        assert_eq!(contents.matches("exited with code 0").count(), 2);
+        assert_eq!(steps.len(), 2);
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let secret = "It's a Secret to Everybody";
+        let body = b"Hello, World!";
+        let headers = |name: &str, value: &str| {
+            let mut headers = HashMap::new();
+            headers.insert(name.to_owned(), value.to_owned());
+            headers
+        };
+
+        let sha256 = get_signature_header(&headers(
+            "x-hub-signature-256",
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
+        ))
+        .unwrap()
+        .unwrap();
+        assert!(verify_signature_header(sha256, secret.to_owned(), body).is_ok());
+
+        let sha1 = get_signature_header(&headers(
+            "x-hub-signature",
+            "sha1=01dc10d0c83e72ed246219cdd91669667fe2ca59",
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            sha1,
+            Signature::Sha1("01dc10d0c83e72ed246219cdd91669667fe2ca59".to_owned())
+        );
+        assert!(verify_signature_header(sha1, secret.to_owned(), body).is_ok());
+
+        let wrong = Signature::Sha1("01dc10d0c83e72ed246219cdd91669667fe2ca58".to_owned());
+        assert!(verify_signature_header(wrong, secret.to_owned(), body).is_err());
+        assert!(get_signature_header(&headers("x-hub-signature", "01dc10d0")).is_err());
+        assert!(matches!(
+            get_signature_header(&headers("x-hub-signature", "sha256=757107ea")),
+            Ok(Some(Signature::Sha256(_)))
+        ));
+        assert_eq!(get_signature_header(&HashMap::new()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_take_lines() {
+        // 'é' is split between two reads
+        let mut pending = b"first\r\nsecond \xc3".to_vec();
+        assert_eq!(take_lines(&mut pending), "data: first\n\n");
+
+        pending.extend_from_slice(b"\xa9t\xc3\xa9\nthird");
+        assert_eq!(take_lines(&mut pending), "data: second \u{e9}t\u{e9}\n\n");
+        assert_eq!(pending, b"third");
+    }
+
+    #[test]
+    fn test_get_bearer_token() {
+        let headers = |authorization: &str| {
+            let mut headers = HashMap::new();
+            headers.insert("authorization".to_owned(), authorization.to_owned());
+            headers
+        };
+
+        assert_eq!(get_bearer_token(&headers("Bearer s3cret")), Some("s3cret"));
+        assert_eq!(get_bearer_token(&headers("bearer s3cret")), Some("s3cret"));
+        assert_eq!(get_bearer_token(&headers("Basic czNjcmV0")), None);
+        assert_eq!(get_bearer_token(&HashMap::new()), None);
+    }
 }
@@ -1,3 +1,80 @@
+use std::fmt;
+use std::io::Write;
+
+use chrono::NaiveDateTime;
+use diesel::deserialize::{self, FromSql};
+use diesel::mysql::Mysql;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
+use serde::Serialize;
+
+/// Where a build is in its lifecycle, stored as text in the builds table
+#[derive(AsExpression, FromSqlRow, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[sql_type = "Text"]
+#[serde(rename_all = "snake_case")]
+pub enum BuildStatus {
+    Queued,
+    Running,
+    Passing,
+    Failing,
+    Cancelled,
+    TimedOut,
+    /// The build couldn't be run, e.g. the project or its .drovah is missing
+    Errored,
+    /// The push didn't match the project's triggers, so nothing was run
+    Skipped,
+}
+
+impl BuildStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BuildStatus::Queued => "queued",
+            BuildStatus::Running => "running",
+            BuildStatus::Passing => "passing",
+            BuildStatus::Failing => "failing",
+            BuildStatus::Cancelled => "cancelled",
+            BuildStatus::TimedOut => "timed_out",
+            BuildStatus::Errored => "errored",
+            BuildStatus::Skipped => "skipped",
+        }
+    }
+
+    /// Whether the build has finished, one way or another
+    pub fn is_finished(self) -> bool {
+        !matches!(self, BuildStatus::Queued | BuildStatus::Running)
+    }
+}
+
+impl fmt::Display for BuildStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl ToSql<Text, Mysql> for BuildStatus {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Mysql>) -> serialize::Result {
+        ToSql::<Text, Mysql>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Mysql> for BuildStatus {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let status: String = FromSql::<Text, Mysql>::from_sql(bytes)?;
+
+        match status.as_str() {
+            "queued" => Ok(BuildStatus::Queued),
+            "running" => Ok(BuildStatus::Running),
+            "passing" => Ok(BuildStatus::Passing),
+            "failing" => Ok(BuildStatus::Failing),
+            "cancelled" => Ok(BuildStatus::Cancelled),
+            "timed_out" => Ok(BuildStatus::TimedOut),
+            "errored" => Ok(BuildStatus::Errored),
+            "skipped" => Ok(BuildStatus::Skipped),
+            _ => Err(format!("Unknown build status '{}'", status).into()),
+        }
+    }
+}
+
 #[derive(Queryable)]
 pub struct Build {
     pub build_id: i32,
@@ -5,10 +82,65 @@ pub struct Build {
     pub build_number: i32,
     pub branch: String,
     pub files: String,
-    pub status: String,
+    pub status: BuildStatus,
+    pub commit_sha: Option<String>,
+    pub pr_number: Option<i32>,
+    pub tag: Option<String>,
+    /// Who the build was triggered by, if the trigger said
+    pub author: Option<String>,
+    /// SHA the ref pointed at before the push which queued the build, if known
+    pub before_sha: Option<String>,
+    /// Files changed by the push which queued the build as a JSON array, if the webhook listed them all
+    pub changed_files: Option<String>,
 }
+
+#[derive(Queryable)]
+pub struct BuildStep {
+    pub build_step_id: i32,
+    pub build_id: i32,
+    pub step_index: i32,
+    pub command: String,
+    pub exit_code: Option<i32>,
+    pub exit_signal: Option<i32>,
+    pub started_at: NaiveDateTime,
+    pub finished_at: NaiveDateTime,
+    pub duration_ms: i64,
+}
+
 #[derive(Queryable)]
 pub struct Project {
     pub project_id: i32,
     pub project_name: String,
+    pub default_branch: String,
+    /// Secret webhooks for the project are signed with, instead of the global one
+    pub webhook_secret: Option<String>,
+    /// Token which has to be given to trigger builds through '/api/v1/trigger/<project>'
+    pub trigger_token: Option<String>,
+    /// JSON object of JSON pointers to read each field of a trigger payload from
+    pub trigger_mapping: Option<String>,
+    /// How often, in seconds, the project's remote is polled for new commits, if at all
+    pub poll_interval: Option<i32>,
+    /// Whether pull requests from forks are built, running whatever their authors put in .drovah
+    pub build_forks: bool,
+}
+
+/// A webhook request as it was received, along with what came of it
+#[derive(Queryable)]
+pub struct WebhookDelivery {
+    pub webhook_delivery_id: i32,
+    /// The id the sender gave the delivery, repeated when it's redelivered
+    pub delivery_id: Option<String>,
+    pub event: String,
+    /// The request's headers, as a JSON object
+    pub headers: String,
+    pub body: String,
+    /// Whether the request passed authentication
+    pub verified: bool,
+    /// Whether the delivery was dealt with, so redeliveries of it can be ignored
+    pub processed: bool,
+    pub outcome: String,
+    pub build_id: Option<i32>,
+    pub received_at: NaiveDateTime,
+    /// The delivery id, on the one delivery of it which was handled, so no redelivery can be handled as well
+    pub claimed_delivery_id: Option<String>,
 }
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use diesel::prelude::*;
+
+use crate::get_latest_build;
+use crate::models::Project;
+use crate::queue::{BuildQueue, BuildRequest};
+use crate::routes::{with_connection, DbPool};
+use crate::schema::projects::dsl as proj;
+use crate::workspace::remote_heads;
+
+/// How often the poller checks whether any project is due to be polled
+/// Poll intervals shorter than this are effectively rounded up to it
+const POLL_TICK: Duration = Duration::from_secs(15);
+
+/// How long a remote has to list its branches, so one which can't be reached doesn't hold up polling the rest
+const POLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Starts polling the remotes of projects with a poll interval, for repositories webhooks can't reach
+/// Each branch whose head has moved since it was last built is queued, and its triggers checked when it's built
+pub(crate) fn start(pool: DbPool, queue: Arc<BuildQueue>) {
+    thread::Builder::new()
+        .name("scm-poller".to_owned())
+        .spawn(move || Poller::default().run(pool, queue))
+        .expect("Failed to start scm poller");
+}
+
+#[derive(Default)]
+struct Poller {
+    /// When each project, by id, was last polled
+    polled: HashMap<i32, Instant>,
+    /// The head last seen of each branch of each project, so heads which weren't built aren't considered again
+    seen: HashMap<(i32, String), String>,
+}
+
+impl Poller {
+    fn run(mut self, pool: DbPool, queue: Arc<BuildQueue>) {
+        loop {
+            self.poll_due(&pool, &queue);
+            thread::sleep(POLL_TICK);
+        }
+    }
+
+    /// Polls every project whose interval has passed since it was last polled
+    /// Connections are only taken for each query, as polling runs git against the remotes
+    fn poll_due(&mut self, pool: &DbPool, queue: &Arc<BuildQueue>) {
+        let projects = with_connection(pool, |database| {
+            proj::projects
+                .filter(proj::poll_interval.gt(0))
+                .load::<Project>(database)
+        });
+
+        let projects = match projects {
+            Some(Ok(projects)) => projects,
+            Some(Err(e)) => {
+                eprintln!("Error getting projects to poll: {}", e);
+                return;
+            }
+            None => return,
+        };
+
+        for project in projects {
+            let interval = Duration::from_secs(project.poll_interval.unwrap_or_default() as u64);
+            let polled = self.polled.get(&project.project_id);
+            let due = polled.is_none_or(|polled| polled.elapsed() >= interval);
+
+            if due {
+                let first = polled.is_none();
+                self.polled.insert(project.project_id, Instant::now());
+                self.poll(pool, queue, &project, first);
+            }
+        }
+    }
+
+    /// Queues a build of each branch of the project whose head isn't what was last built of it
+    /// The first poll only takes note of branches which have never been built, which are built once their head moves
+    fn poll(&mut self, pool: &DbPool, queue: &Arc<BuildQueue>, project: &Project, first: bool) {
+        let project_path = format!("data/projects/{}/", project.project_name);
+        if !Path::new(&project_path).exists() {
+            return;
+        }
+
+        let heads = match remote_heads(&project_path, POLL_TIMEOUT) {
+            Ok(heads) => heads,
+            Err(e) => {
+                eprintln!("Error polling {}: {}", project_path, e);
+                return;
+            }
+        };
+
+        for (branch, head) in heads {
+            let key = (project.project_id, branch.clone());
+            if self.seen.get(&key) == Some(&head) {
+                continue;
+            }
+
+            // Heads whose last build couldn't be looked up are left to be looked at again on the next poll
+            let last_built = match with_connection(pool, |database| {
+                get_latest_build(database, project.project_id, &branch)
+            }) {
+                Some(build) => build.and_then(|build| build.commit_sha),
+                None => continue,
+            };
+            if last_built.as_deref() == Some(head.as_str()) || (first && last_built.is_none()) {
+                self.seen.insert(key, head);
+                continue;
+            }
+
+            println!(
+                "Polled new commit {} on '{}' of '{}'",
+                head, branch, project.project_name
+            );
+
+            let request = BuildRequest {
+                project_id: project.project_id,
+                branch,
+                commit_sha: Some(head.clone()),
+                pr_number: None,
+                tag: None,
+                author: None,
+                before_sha: last_built,
+                changed_files: None,
+            };
+
+            // Heads which couldn't be queued are left to be tried again on the next poll
+            match queue.enqueue(request) {
+                Ok(_) => {
+                    self.seen.insert(key, head);
+                }
+                Err(e) => eprintln!("Error queueing build of {}: {}", project_path, e),
+            }
+        }
+    }
+}
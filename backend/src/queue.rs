@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use diesel::{insert_into, prelude::*, update};
+
+use crate::models::{Build, BuildStatus};
+use crate::routes::{with_connection, DbPool};
+use crate::schema::builds::dsl as build;
+use crate::triggers::GitRef;
+use crate::{get_build, get_build_number, get_project_name, run_build};
+
+/// A build claimed from the queue, which is currently running
+struct RunningBuild {
+    project_id: i32,
+    build_number: i32,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// The result of asking for a build to be cancelled
+pub(crate) enum Cancellation {
+    /// The build is running, and has been flagged to stop
+    Cancelling,
+    /// The build was waiting in the queue, and has been removed
+    Dequeued,
+    /// The build has already finished
+    Finished,
+    NotFound,
+}
+
+/// What to build, as described by whatever triggered the build
+pub(crate) struct BuildRequest {
+    pub(crate) project_id: i32,
+    pub(crate) branch: String,
+    /// Without a commit, the head of the branch is built
+    pub(crate) commit_sha: Option<String>,
+    /// Set for builds of a pull request, whose head is built rather than the branch
+    pub(crate) pr_number: Option<i32>,
+    /// Set for builds of a tag, which aren't builds of any branch
+    pub(crate) tag: Option<String>,
+    pub(crate) author: Option<String>,
+    /// SHA the ref pointed at before the push, which the build's triggers compare paths against
+    pub(crate) before_sha: Option<String>,
+    /// Files changed by the push, if the webhook listed them all
+    pub(crate) changed_files: Option<Vec<String>>,
+}
+
+/// Removes a build from 'running' once its worker is done with it, then starts the next one
+/// Being dropped as the worker unwinds, a build which panics doesn't keep hold of its slot
+struct RunningGuard<'a> {
+    queue: &'a Arc<BuildQueue>,
+    build_id: i32,
+}
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            set_build_status(&self.queue.pool, self.build_id, BuildStatus::Errored);
+        }
+
+        self.queue.running().remove(&self.build_id);
+        self.queue.dispatch();
+    }
+}
+
+/// A build claimed from the queue, waiting for a worker to pick it up
+struct ClaimedBuild {
+    build: Build,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Queue of builds waiting to run
+/// Queued builds are stored in the builds table with a 'queued' status, so they survive restarts
+/// Builds are started as soon as there is room under both the global and per-project limits,
+/// and are run on a dedicated pool of worker threads rather than on the async runtime
+pub struct BuildQueue {
+    pool: DbPool,
+    max_builds: usize,
+    max_builds_per_project: usize,
+    /// Hands claimed builds to the workers, of which there are 'max_builds'
+    workers: Mutex<Sender<ClaimedBuild>>,
+    claimed: Mutex<Receiver<ClaimedBuild>>,
+    /// Builds currently running, keyed by build id
+    /// Held while dispatching, so limits can't be exceeded by builds finishing at the same time
+    running: Mutex<HashMap<i32, RunningBuild>>,
+    /// Held while adding builds, so two builds of a project can't be given the same number
+    enqueueing: Mutex<()>,
+}
+
+impl BuildQueue {
+    /// Creates the queue and its workers
+    /// Limits are taken from MAX_BUILDS (default 2) and MAX_BUILDS_PER_PROJECT (default 1)
+    pub fn start(pool: DbPool) -> Arc<Self> {
+        let (sender, receiver) = mpsc::channel();
+
+        let queue = Arc::new(BuildQueue {
+            pool,
+            max_builds: limit_from_env("MAX_BUILDS", 2),
+            max_builds_per_project: limit_from_env("MAX_BUILDS_PER_PROJECT", 1),
+            workers: Mutex::new(sender),
+            claimed: Mutex::new(receiver),
+            running: Mutex::new(HashMap::new()),
+            enqueueing: Mutex::new(()),
+        });
+
+        for worker in 0..queue.max_builds {
+            let queue = queue.clone();
+
+            thread::Builder::new()
+                .name(format!("build-worker-{}", worker))
+                .spawn(move || queue.work())
+                .expect("Failed to start build worker");
+        }
+
+        queue
+    }
+
+    /// Runs claimed builds, one at a time, for as long as drovah runs
+    /// A build which panics is recorded as errored, and the worker carries on with the next
+    fn work(self: Arc<Self>) {
+        loop {
+            let claimed = self.claimed.lock().unwrap().recv();
+
+            match claimed {
+                Ok(claimed) => {
+                    let executed = panic::catch_unwind(AssertUnwindSafe(|| {
+                        self.execute(claimed.build, claimed.cancelled)
+                    }));
+
+                    if executed.is_err() {
+                        eprintln!("Build worker recovered from a panicked build");
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Locks the builds currently running
+    /// A worker panicking while holding the lock doesn't leave it unusable, as 'running' is never left half updated
+    fn running(&self) -> MutexGuard<'_, HashMap<i32, RunningBuild>> {
+        self.running.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds a build to the queue, returning its build id
+    /// Shouldn't be called while holding a connection, as starting the build takes one of its own
+    pub(crate) fn enqueue(self: &Arc<Self>, request: BuildRequest) -> Result<i32, Box<dyn Error>> {
+        let changed_files = match &request.changed_files {
+            Some(changed) => Some(serde_json::to_string(changed)?),
+            None => None,
+        };
+
+        let build_id = {
+            let database = self.pool.get()?;
+            let _enqueueing = self.enqueueing.lock().unwrap();
+            let build_number = get_build_number(&database, request.project_id) + 1;
+
+            insert_into(build::builds)
+                .values((
+                    build::project_id.eq(request.project_id),
+                    build::build_number.eq(build_number),
+                    build::branch.eq(request.branch),
+                    build::files.eq("".to_owned()),
+                    build::status.eq(BuildStatus::Queued),
+                    build::commit_sha.eq(request.commit_sha),
+                    build::pr_number.eq(request.pr_number),
+                    build::tag.eq(request.tag),
+                    build::author.eq(request.author),
+                    build::before_sha.eq(request.before_sha),
+                    build::changed_files.eq(changed_files),
+                ))
+                .execute(&database)?;
+
+            build::builds
+                .filter(build::project_id.eq(request.project_id))
+                .filter(build::build_number.eq(build_number))
+                .select(build::build_id)
+                .first(&database)?
+        };
+
+        self.dispatch();
+        Ok(build_id)
+    }
+
+    /// Requeues any builds which were interrupted by drovah stopping, and starts queued builds
+    /// Should be called once on startup
+    pub fn recover(self: &Arc<Self>) {
+        let requeued = with_connection(&self.pool, |database| {
+            update(build::builds.filter(build::status.eq(BuildStatus::Running)))
+                .set(build::status.eq(BuildStatus::Queued))
+                .execute(database)
+        });
+
+        match requeued {
+            Some(Ok(0)) | None => {}
+            Some(Ok(requeued)) => println!("Requeued {} interrupted build(s)", requeued),
+            Some(Err(e)) => eprintln!("Error requeueing interrupted builds: {}", e),
+        }
+
+        self.dispatch();
+    }
+
+    /// Cancels a build, whether it is running or still queued
+    pub(crate) fn cancel(&self, project_id: i32, build_number: i32) -> Cancellation {
+        {
+            let running = self.running();
+            let running_build = running.values().find(|running_build| {
+                running_build.project_id == project_id && running_build.build_number == build_number
+            });
+
+            if let Some(running_build) = running_build {
+                running_build.cancelled.store(true, Ordering::SeqCst);
+                return Cancellation::Cancelling;
+            }
+        }
+
+        let database = match self.pool.get() {
+            Ok(database) => database,
+            Err(e) => {
+                eprintln!(
+                    "Error getting db connection to cancel build {}: {}",
+                    build_number, e
+                );
+                return Cancellation::NotFound;
+            }
+        };
+
+        let dequeued = update(
+            build::builds
+                .filter(build::project_id.eq(project_id))
+                .filter(build::build_number.eq(build_number))
+                .filter(build::status.eq(BuildStatus::Queued)),
+        )
+        .set(build::status.eq(BuildStatus::Cancelled))
+        .execute(&database);
+
+        match dequeued {
+            Ok(1) => Cancellation::Dequeued,
+            Ok(_) => match get_build(&database, project_id, build_number) {
+                Some(_) => Cancellation::Finished,
+                None => Cancellation::NotFound,
+            },
+            Err(e) => {
+                eprintln!("Error cancelling queued build {}: {}", build_number, e);
+                Cancellation::NotFound
+            }
+        }
+    }
+
+    /// Removes queued builds of a branch or tag from the queue, returning how many were removed
+    pub(crate) fn dequeue_ref(
+        &self,
+        project_id: i32,
+        git_ref: &GitRef,
+    ) -> Result<usize, Box<dyn Error>> {
+        let database = self.pool.get()?;
+
+        let queued = build::builds
+            .filter(build::project_id.eq(project_id))
+            .filter(build::status.eq(BuildStatus::Queued));
+
+        let dequeued = match git_ref {
+            GitRef::Branch(branch) => update(
+                queued
+                    .filter(build::branch.eq(branch))
+                    .filter(build::pr_number.is_null())
+                    .filter(build::tag.is_null()),
+            )
+            .set(build::status.eq(BuildStatus::Cancelled))
+            .execute(&database),
+            GitRef::Tag(tag) => update(queued.filter(build::tag.eq(tag)))
+                .set(build::status.eq(BuildStatus::Cancelled))
+                .execute(&database),
+            GitRef::PullRequest(number) => update(queued.filter(build::pr_number.eq(number)))
+                .set(build::status.eq(BuildStatus::Cancelled))
+                .execute(&database),
+        }?;
+
+        Ok(dequeued)
+    }
+
+    /// Starts as many queued builds as the limits allow, oldest first
+    fn dispatch(self: &Arc<Self>) {
+        let database = match self.pool.get() {
+            Ok(database) => database,
+            Err(e) => {
+                eprintln!("Error getting db connection to start queued builds: {}", e);
+                return;
+            }
+        };
+        let mut running = self.running();
+
+        if running.len() >= self.max_builds {
+            return;
+        }
+
+        let queued = match build::builds
+            .filter(build::status.eq(BuildStatus::Queued))
+            .order(build::build_id.asc())
+            .load::<Build>(&database)
+        {
+            Ok(queued) => queued,
+            Err(e) => {
+                eprintln!("Error loading queued builds: {}", e);
+                return;
+            }
+        };
+
+        for queued_build in queued {
+            if running.len() >= self.max_builds {
+                break;
+            }
+
+            let running_for_project = running
+                .values()
+                .filter(|running_build| running_build.project_id == queued_build.project_id)
+                .count();
+
+            if running_for_project >= self.max_builds_per_project {
+                continue;
+            }
+
+            // Claim the build, unless it was cancelled in the meantime
+            let claimed = update(
+                build::builds
+                    .find(queued_build.build_id)
+                    .filter(build::status.eq(BuildStatus::Queued)),
+            )
+            .set(build::status.eq(BuildStatus::Running))
+            .execute(&database);
+
+            match claimed {
+                Ok(1) => {}
+                Ok(_) => continue,
+                Err(e) => {
+                    eprintln!("Error claiming build {}: {}", queued_build.build_id, e);
+                    continue;
+                }
+            }
+
+            let cancelled = Arc::new(AtomicBool::new(false));
+            running.insert(
+                queued_build.build_id,
+                RunningBuild {
+                    project_id: queued_build.project_id,
+                    build_number: queued_build.build_number,
+                    cancelled: cancelled.clone(),
+                },
+            );
+
+            let claimed = ClaimedBuild {
+                build: queued_build,
+                cancelled,
+            };
+
+            if let Err(e) = self.workers.lock().unwrap().send(claimed) {
+                eprintln!("Error handing build to workers: {}", e);
+            }
+        }
+    }
+
+    /// Runs a claimed build, then makes room for the next one
+    /// Connections are only taken for as long as each query needs, rather than for the whole build
+    fn execute(self: &Arc<Self>, queued_build: Build, cancelled: Arc<AtomicBool>) {
+        let _running = RunningGuard {
+            queue: self,
+            build_id: queued_build.build_id,
+        };
+
+        let project = with_connection(&self.pool, |database| {
+            get_project_name(database, queued_build.project_id)
+        });
+
+        if let Some(Some(project)) = project {
+            if let Err(e) = run_build(project, &queued_build, &cancelled, &self.pool) {
+                eprintln!("Error! {}", e);
+                set_build_status(&self.pool, queued_build.build_id, BuildStatus::Errored);
+            }
+        } else {
+            set_build_status(&self.pool, queued_build.build_id, BuildStatus::Errored);
+        }
+    }
+}
+
+/// Sets the status of a build, for builds which couldn't be run
+fn set_build_status(pool: &DbPool, build_id: i32, status: BuildStatus) {
+    let updated = with_connection(pool, |database| {
+        update(build::builds.find(build_id))
+            .set(build::status.eq(status))
+            .execute(database)
+    });
+
+    if let Some(Err(e)) = updated {
+        eprintln!("Error setting status of build {}: {}", build_id, e);
+    }
+}
+
+/// Reads a limit on running builds from the environment
+fn limit_from_env(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(limit) => match limit.parse() {
+            Ok(limit) if limit > 0 => limit,
+            _ => {
+                eprintln!("Invalid {} '{}', using {}", name, limit, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
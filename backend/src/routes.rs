@@ -1,7 +1,8 @@
-use std::fs;
 use std::path::Path;
+use std::{env, fs};
 
 use actix_files::NamedFile;
+use actix_web::http::header;
 use actix_web::web::Data;
 use actix_web::{get, post, web, HttpResponse};
 use diesel::MysqlConnection;
@@ -9,26 +10,108 @@ use serde_json::json;
 
 use diesel::r2d2::{self, ConnectionManager};
 
-use crate::get_headers_hash_map;
+use crate::models::Project;
+use crate::queue::{BuildQueue, Cancellation};
 use crate::{
-    get_build_number, get_latest_build_status, get_project_data, get_project_id,
-    get_project_status_badge, get_status_for_build, run_build, run_commands,
-    verify_authentication_header, WebhookData,
+    archive_dir, constant_time_eq, get_bearer_token, get_build, get_default_branch,
+    get_headers_hash_map, get_latest_build_status, get_latest_passing_build, get_project,
+    get_project_data, get_project_id, get_project_status_badge, get_status_for_build,
+    get_tag_build, tail_build_log, BranchQuery,
 };
 
-type DbPool = r2d2::Pool<ConnectionManager<MysqlConnection>>;
+pub(crate) type DbPool = r2d2::Pool<ConnectionManager<MysqlConnection>>;
+
+/// Runs blocking database work on the blocking thread pool, so it doesn't stall the worker serving requests
+pub(crate) async fn with_database<F, T>(pool: &Data<DbPool>, work: F) -> actix_web::Result<T>
+where
+    F: FnOnce(&MysqlConnection) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.get_ref().clone();
+
+    let result = web::block(move || {
+        let database = pool.get()?;
+        Ok::<T, r2d2::PoolError>(work(&database))
+    })
+    .await?;
+
+    Ok(result)
+}
+
+/// Runs blocking work which doesn't need the database, such as git commands, on the blocking thread pool
+/// Any database work it does should take its own connection for only as long as it needs one
+pub(crate) async fn run_blocking<F, T>(work: F) -> actix_web::Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = web::block(move || Ok::<T, ()>(work())).await?;
+
+    Ok(result)
+}
+
+/// Runs database work on a connection of its own, for threads outside the async runtime such as build workers
+/// The connection is only held for the work, and failing to get one is reported rather than panicking
+pub(crate) fn with_connection<F, T>(pool: &DbPool, work: F) -> Option<T>
+where
+    F: FnOnce(&MysqlConnection) -> T,
+{
+    match pool.get() {
+        Ok(database) => Some(work(&database)),
+        Err(e) => {
+            eprintln!("Error getting db connection: {}", e);
+            None
+        }
+    }
+}
 
 /// Returns specific file
+/// Files of pull request builds are looked for where those are archived
 #[get("/api/v1/{project}/{build}/{file}")]
 pub(crate) async fn get_file_for_build(
-    path: web::Path<(String, String, String)>,
+    path: web::Path<(String, i32, String)>,
+    pool: Data<DbPool>,
 ) -> actix_web::Result<NamedFile> {
     let inner = path.into_inner();
     let project = inner.0;
     let build = inner.1;
     let file = inner.2;
 
-    let formatted = format!("data/archive/{}/{}/{}", project, build, file);
+    let project_name = project.clone();
+    let pr_number = with_database(&pool, move |database| {
+        let project_id = get_project_id(database, &project_name)?;
+        get_build(database, project_id, build)?.pr_number
+    })
+    .await?;
+
+    let formatted = format!("{}/{}", archive_dir(&project, build, pr_number), file);
+
+    let p = Path::new(&formatted);
+
+    actix_web::Result::Ok(NamedFile::open(p)?)
+}
+
+/// Returns specific file from the latest build of a tag
+/// Tags may contain slashes, so only the last segment is taken as the file
+#[get("/api/v1/{project}/tags/{tag:.+}/{file}")]
+pub(crate) async fn get_file_for_tag(
+    path: web::Path<(String, String, String)>,
+    pool: Data<DbPool>,
+) -> actix_web::Result<NamedFile> {
+    let inner = path.into_inner();
+    let project = inner.0;
+    let tag = inner.1;
+    let file = inner.2;
+
+    let project_name = project.clone();
+    let build_number = with_database(&pool, move |database| {
+        let project_id = get_project_id(database, &project_name)?;
+        Some(get_tag_build(database, project_id, &tag)?.build_number)
+    })
+    .await?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("No build of tag found"))?;
+
+    let formatted = format!("{}/{}", archive_dir(&project, build_number, None), file);
 
     let p = Path::new(&formatted);
 
@@ -40,47 +123,62 @@ pub(crate) async fn get_file_for_build(
 #[get("/api/v1/projects")]
 pub(crate) async fn get_project_information(pool: Data<DbPool>) -> actix_web::Result<HttpResponse> {
     let dir = Path::new("data/projects/");
-    let database = pool.get().expect("couldn't get db connection from pool");
 
-    let mut projects = vec![];
+    let mut project_names = vec![];
     for entry in fs::read_dir(dir)? {
         let entry = entry?;
         let path = entry.path();
         if path.is_dir() {
             if let Ok(file_name) = entry.file_name().into_string() {
-                let project_id = get_project_id(&database, &file_name);
-                if let Some(project_id) = project_id {
-                    let project_data = get_project_data(&database, project_id);
-                    projects.push(project_data);
-                }
+                project_names.push(file_name);
             }
         }
     }
 
+    let projects = with_database(&pool, move |database| {
+        let mut projects = vec![];
+        for project_name in project_names {
+            let project_id = get_project_id(database, &project_name);
+            if let Some(project_id) = project_id {
+                let project_data = get_project_data(database, project_id);
+                projects.push(project_data);
+            }
+        }
+        projects
+    })
+    .await?;
+
     let json_result = json!({ "projects": projects });
 
     actix_web::Result::Ok(HttpResponse::Ok().json(json_result))
 }
 
 /// Returns latest status badge for given project
+/// Reports on the project's default branch, unless another is given with '?branch='
 #[get("/api/v1/{project}/badge")]
 pub(crate) async fn get_latest_status_badge(
     project: web::Path<(String,)>,
+    query: web::Query<BranchQuery>,
     pool: Data<DbPool>,
-) -> HttpResponse {
-    let database = pool.get().expect("couldn't get db connection from pool");
-    let project_id = get_project_id(&database, &project.into_inner().0);
-
-    if let Some(project_id) = project_id {
-        let latest_status = get_latest_build_status(&database, project_id);
+) -> actix_web::Result<HttpResponse> {
+    let project = project.into_inner().0;
+    let branch = query.into_inner().branch;
+    let latest_status = with_database(&pool, move |database| {
+        let project_id = get_project_id(database, &project)?;
+        let branch = branch.or_else(|| get_default_branch(database, project_id))?;
+        Some(get_latest_build_status(database, project_id, &branch))
+    })
+    .await?;
+
+    if let Some(latest_status) = latest_status {
         let badge = get_project_status_badge(latest_status);
 
         if !badge.is_empty() {
-            return HttpResponse::Ok().content_type("image/svg+xml").body(badge);
+            return Ok(HttpResponse::Ok().content_type("image/svg+xml").body(badge));
         }
     }
 
-    HttpResponse::NotFound().finish()
+    Ok(HttpResponse::NotFound().finish())
 }
 
 /// Returns status badge for specific build
@@ -88,91 +186,156 @@ pub(crate) async fn get_latest_status_badge(
 pub(crate) async fn get_status_badge_for_build(
     path: web::Path<(String, i32)>,
     pool: Data<DbPool>,
-) -> HttpResponse {
-    let database = pool.get().expect("couldn't get db connection from pool");
+) -> actix_web::Result<HttpResponse> {
     let inner = path.into_inner();
     let project = inner.0;
     let build = inner.1;
-    let project_id = get_project_id(&database, &project);
+    let status = with_database(&pool, move |database| {
+        let project_id = get_project_id(database, &project)?;
+        get_status_for_build(database, project_id, build)
+    })
+    .await?;
 
-    if let Some(project_id) = project_id {
-        let status = get_status_for_build(&database, project_id, build);
-        let status_badge = get_project_status_badge(status);
+    if let Some(status) = status {
+        let status_badge = get_project_status_badge(Some(status));
 
         if !status_badge.is_empty() {
-            return HttpResponse::Ok()
+            return Ok(HttpResponse::Ok()
                 .content_type("image/svg+xml")
-                .body(status_badge);
+                .body(status_badge));
         }
     }
 
-    HttpResponse::NotFound().finish()
+    Ok(HttpResponse::NotFound().finish())
 }
 
-/// Handles webhook
-/// targeted towards GitHub's webhook
-/// however, would support others as long as they adhere to format
-/// URL is <host>:<port>/webhook
-#[post("/api/v1/webhook")]
-pub(crate) async fn github_webhook(
-    request: web::HttpRequest,
-    body: web::Bytes,
+/// Streams the log of a build as Server-Sent Events
+/// Each line of output is sent as it is written, finishing with a 'status' event once the build is done
+/// Builds which don't exist, or no longer do, have nothing to stream
+#[get("/api/v1/{project}/{build}/log/stream")]
+pub(crate) async fn stream_build_log(
+    path: web::Path<(String, i32)>,
     pool: Data<DbPool>,
 ) -> actix_web::Result<HttpResponse> {
-    // Begin github secret auth
-    let body: Vec<u8> = body.to_vec();
-    let headers = get_headers_hash_map(request.headers())?;
+    let inner = path.into_inner();
+    let project = inner.0;
+    let build = inner.1;
+
+    let project_name = project.clone();
+    let found = with_database(&pool, move |database| {
+        let project_id = get_project_id(database, &project_name)?;
+        let build = get_build(database, project_id, build);
+        Some((project_id, build))
+    })
+    .await?;
+
+    if let Some((project_id, Some(saved))) = found {
+        let log_path = format!(
+            "{}/build.log",
+            archive_dir(&project, build, saved.pr_number)
+        );
+        let pool = pool.get_ref().clone();
+        let events = tail_build_log(log_path, pool, project_id, build);
+
+        return Ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .streaming(Box::pin(events)));
+    }
 
-    verify_authentication_header(&headers, &body)?;
+    Ok(HttpResponse::NotFound().finish())
+}
+
+/// Cancels a running or queued build
+/// A running build has its processes killed and cleanup steps run, before it is saved as 'cancelled'
+/// Authenticated by an 'Authorization: Bearer <token>' header, see 'verify_cancel_token'
+#[post("/api/v1/{project}/{build}/cancel")]
+pub(crate) async fn cancel_build(
+    request: web::HttpRequest,
+    path: web::Path<(String, i32)>,
+    pool: Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<HttpResponse> {
+    let inner = path.into_inner();
+    let project = inner.0;
+    let build = inner.1;
+    let queue = queue.into_inner();
 
-    // Parse json manually as actix doesn't support multiple extractors
-    let webhookdata: WebhookData = serde_json::from_slice(&body).expect("Json error");
+    let headers = get_headers_hash_map(request.headers())?;
+    let stored = with_database(&pool, move |database| get_project(database, &project)).await?;
+    if let Err(response) = verify_cancel_token(stored.as_ref(), get_bearer_token(&headers)) {
+        return Ok(response);
+    }
 
-    let project_path = format!("data/projects/{}/", &webhookdata.repository.name);
-    let path = Path::new(&project_path);
-    if path.exists() {
-        tokio::spawn(async move {
-            let commands = vec!["git pull".to_owned()];
-            run_commands(commands, &project_path, false);
+    let cancellation = match stored {
+        Some(stored) => Some(run_blocking(move || queue.cancel(stored.project_id, build)).await?),
+        None => None,
+    };
 
-            let database = pool.get().expect("couldn't get db connection from pool");
+    let response = match cancellation {
+        Some(Cancellation::Cancelling) => HttpResponse::Accepted().body("Cancelling build"),
+        Some(Cancellation::Dequeued) => HttpResponse::Ok().body("Build removed from queue"),
+        Some(Cancellation::Finished) => HttpResponse::Conflict().body("Build has already finished"),
+        Some(Cancellation::NotFound) | None => HttpResponse::NotFound().finish(),
+    };
 
-            if let Err(e) = run_build(webhookdata.repository.name, &database) {
-                eprintln!("Error! {}", e);
-            }
-        });
+    Ok(response)
+}
 
-        return actix_web::Result::Ok(HttpResponse::NoContent().finish());
+/// Checks the token is either the ADMIN_TOKEN environment variable, or the project's trigger token
+/// Projects which don't exist are reported as such once authenticated by ADMIN_TOKEN, so as not to give their names away
+fn verify_cancel_token(project: Option<&Project>, token: Option<&str>) -> Result<(), HttpResponse> {
+    let admin_token = env::var("ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty());
+    let trigger_token = project.and_then(|project| project.trigger_token.clone());
+
+    let token = match token {
+        Some(token) => token,
+        None => return Err(HttpResponse::Unauthorized().body("Missing token")),
+    };
+
+    let allowed = admin_token
+        .iter()
+        .chain(trigger_token.iter())
+        .any(|allowed| constant_time_eq(allowed.as_bytes(), token.as_bytes()));
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(HttpResponse::Unauthorized().body("Invalid token"))
     }
-
-    actix_web::Result::Ok(HttpResponse::NotAcceptable().body("Project doesn't exist"))
 }
 
 /// Returns latest file
+/// Taken from the latest build of the project's default branch, unless another is given with '?branch='
 /// If one does not exist, will just return an os error of not found
 #[get("/api/v1/{project}/latest")]
 pub(crate) async fn get_latest_file(
     project: web::Path<(String,)>,
+    query: web::Query<BranchQuery>,
     pool: Data<DbPool>,
 ) -> actix_web::Result<NamedFile> {
-    let database = pool.get().expect("couldn't get db connection from pool");
     let project = project.into_inner().0;
-    let project_id = get_project_id(&database, &project).unwrap();
-
-    let build_number = get_build_number(&database, project_id);
-
-    let path_str = format!("data/archive/{}/{}/", &project, build_number);
+    let project_name = project.clone();
+    let branch = query.into_inner().branch;
+    let build_number = with_database(&pool, move |database| {
+        let project_id = get_project_id(database, &project_name)?;
+        let branch = branch.or_else(|| get_default_branch(database, project_id))?;
+        Some(get_latest_passing_build(database, project_id, &branch)?.build_number)
+    })
+    .await?
+    .ok_or_else(|| actix_web::error::ErrorNotFound("No passing builds found"))?;
+
+    let path_str = archive_dir(&project, build_number, None);
     let path = Path::new(&path_str);
 
-    let dir = fs::read_dir(path)?;
-
-    for file in dir.flatten() {
-        if !file.path().extension().unwrap().eq("log") {
+    // The build's log lives alongside its archived files, but isn't one of them
+    for file in fs::read_dir(path)?.flatten() {
+        if file.file_name() != "build.log" {
             return actix_web::Result::Ok(NamedFile::open(file.path())?);
         }
     }
 
-    let file = NamedFile::open(fs::read_dir(path)?.last().unwrap()?.path())?;
-
-    actix_web::Result::Ok(file)
+    Err(actix_web::error::ErrorNotFound("No files archived"))
 }
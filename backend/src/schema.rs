@@ -1,3 +1,17 @@
+table! {
+    build_steps (build_step_id) {
+        build_step_id -> Integer,
+        build_id -> Integer,
+        step_index -> Integer,
+        command -> Text,
+        exit_code -> Nullable<Integer>,
+        exit_signal -> Nullable<Integer>,
+        started_at -> Datetime,
+        finished_at -> Datetime,
+        duration_ms -> Bigint,
+    }
+}
+
 table! {
     builds (build_id) {
         build_id -> Integer,
@@ -6,6 +20,12 @@ table! {
         branch -> Text,
         files -> Text,
         status -> Text,
+        commit_sha -> Nullable<Varchar>,
+        pr_number -> Nullable<Integer>,
+        tag -> Nullable<Varchar>,
+        author -> Nullable<Varchar>,
+        before_sha -> Nullable<Varchar>,
+        changed_files -> Nullable<Text>,
     }
 }
 
@@ -13,7 +33,29 @@ table! {
     projects (project_id) {
         project_id -> Integer,
         project_name -> Text,
+        default_branch -> Varchar,
+        webhook_secret -> Nullable<Varchar>,
+        trigger_token -> Nullable<Varchar>,
+        trigger_mapping -> Nullable<Text>,
+        poll_interval -> Nullable<Integer>,
+        build_forks -> Bool,
+    }
+}
+
+table! {
+    webhook_deliveries (webhook_delivery_id) {
+        webhook_delivery_id -> Integer,
+        delivery_id -> Nullable<Varchar>,
+        event -> Varchar,
+        headers -> Text,
+        body -> Text,
+        verified -> Bool,
+        processed -> Bool,
+        outcome -> Text,
+        build_id -> Nullable<Integer>,
+        received_at -> Datetime,
+        claimed_delivery_id -> Nullable<Varchar>,
     }
 }
 
-allow_tables_to_appear_in_same_query!(builds, projects,);
+allow_tables_to_appear_in_same_query!(build_steps, builds, projects, webhook_deliveries,);
@@ -0,0 +1,224 @@
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+
+/// A ref pushed to a repository
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum GitRef {
+    Branch(String),
+    Tag(String),
+    /// The head of a pull request, by number
+    PullRequest(i32),
+}
+
+impl GitRef {
+    /// Parses a full ref, e.g. 'refs/heads/main', returning None for anything but branches and tags
+    pub(crate) fn parse(full_ref: &str) -> Option<Self> {
+        if let Some(branch) = full_ref.strip_prefix("refs/heads/") {
+            Some(GitRef::Branch(branch.to_owned()))
+        } else {
+            full_ref
+                .strip_prefix("refs/tags/")
+                .map(|tag| GitRef::Tag(tag.to_owned()))
+        }
+    }
+
+    /// The revision the ref points at in the project's clone, once fetched
+    pub(crate) fn revision(&self) -> String {
+        match self {
+            GitRef::Branch(branch) => format!("origin/{}", branch),
+            GitRef::Tag(tag) => format!("refs/tags/{}", tag),
+            GitRef::PullRequest(number) => format!("origin/pull/{}", number),
+        }
+    }
+
+    /// What has to be fetched for the ref, beyond the remote's branches and tags
+    /// Hosts keep pull request heads under different refs (GitHub's and Gitea's, GitLab's, then
+    /// Bitbucket Server's), so only one will exist
+    pub(crate) fn refspecs(&self) -> Vec<String> {
+        match self {
+            GitRef::PullRequest(number) => [
+                "pull/{}/head",
+                "merge-requests/{}/head",
+                "pull-requests/{}/from",
+            ]
+            .iter()
+            .map(|head| {
+                format!(
+                    "+refs/{}:refs/remotes/origin/pull/{}",
+                    head.replace("{}", &number.to_string()),
+                    number
+                )
+            })
+            .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// A push to a repository, as described by a webhook
+pub(crate) struct PushEvent {
+    pub(crate) git_ref: GitRef,
+    /// SHA the ref pointed at before the push
+    pub(crate) before: Option<String>,
+    /// SHA the ref points at after the push
+    pub(crate) after: Option<String>,
+    /// Files added, modified or removed by the pushed commits, if the webhook listed them all
+    pub(crate) changed_files: Option<Vec<String>>,
+    /// Who made the push, if the webhook said
+    pub(crate) author: Option<String>,
+}
+
+/// Whether a SHA is all zeros, which webhooks use for a ref that doesn't exist (yet, or any more)
+pub(crate) fn is_null_sha(sha: &str) -> bool {
+    sha.bytes().all(|b| b == b'0')
+}
+
+/// Whether a string names a commit by (possibly abbreviated) SHA, rather than anything git could take as an option
+pub(crate) fn is_object_name(sha: &str) -> bool {
+    (4..=40).contains(&sha.len()) && sha.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Represents the triggers section of .drovah
+/// Leaving out a list of branches, tags or paths means all of them are built
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct TriggerConfig {
+    branches: Option<Vec<String>>,
+    #[serde(default)]
+    branches_ignore: Vec<String>,
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    tags_ignore: Vec<String>,
+    paths: Option<Vec<String>>,
+    #[serde(default)]
+    paths_ignore: Vec<String>,
+}
+
+impl TriggerConfig {
+    /// Whether a push to the given ref should be built
+    pub(crate) fn allows(&self, git_ref: &GitRef) -> bool {
+        match git_ref {
+            GitRef::Branch(branch) => {
+                filter_allows(self.branches.as_deref(), &self.branches_ignore, branch)
+            }
+            GitRef::Tag(tag) => filter_allows(self.tags.as_deref(), &self.tags_ignore, tag),
+            GitRef::PullRequest(_) => true,
+        }
+    }
+
+    /// Whether builds depend on which files were changed
+    pub(crate) fn filters_paths(&self) -> bool {
+        self.paths.is_some() || !self.paths_ignore.is_empty()
+    }
+
+    /// Whether a push changing the given files should be built, i.e. any of them pass the path filters
+    /// Pushes which don't change any files are always built
+    pub(crate) fn allows_paths(&self, changed_files: &[String]) -> bool {
+        changed_files.is_empty()
+            || changed_files
+                .iter()
+                .any(|file| filter_allows(self.paths.as_deref(), &self.paths_ignore, file))
+    }
+}
+
+/// Checks a name against include and exclude patterns, exclusions taking priority
+fn filter_allows(include: Option<&[String]>, exclude: &[String], name: &str) -> bool {
+    let included =
+        include.is_none_or(|include| include.iter().any(|pattern| glob_matches(pattern, name)));
+
+    included && !exclude.iter().any(|pattern| glob_matches(pattern, name))
+}
+
+/// Matches a name against a glob, where '*' stays within a path segment and '**' spans segments
+/// Invalid patterns never match
+pub(crate) fn glob_matches(pattern: &str, name: &str) -> bool {
+    let options = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+
+    match Pattern::new(pattern) {
+        Ok(pattern) => pattern.matches_with(name, options),
+        Err(e) => {
+            eprintln!("Invalid pattern '{}' in .drovah: {}", pattern, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_branch_filters() {
+        let triggers: TriggerConfig = toml::from_str(
+            r#"
+            branches = ["main", "release/*"]
+            branches_ignore = ["release/old-*"]
+            tags_ignore = ["*-rc*"]
+            "#,
+        )
+        .unwrap();
+
+        let branch = |name: &str| GitRef::Branch(name.to_owned());
+        let tag = |name: &str| GitRef::Tag(name.to_owned());
+
+        assert!(triggers.allows(&branch("main")));
+        assert!(triggers.allows(&branch("release/1.0")));
+        assert!(!triggers.allows(&branch("release/old-1.0")));
+        assert!(!triggers.allows(&branch("release/1.0/hotfix")));
+        assert!(!triggers.allows(&branch("feature/thing")));
+        assert!(triggers.allows(&tag("v1.0")));
+        assert!(!triggers.allows(&tag("v1.0-rc1")));
+    }
+
+    #[test]
+    fn test_path_filters() {
+        let triggers: TriggerConfig = toml::from_str(
+            r#"
+            paths = ["src/**"]
+            paths_ignore = ["**/*.md"]
+            "#,
+        )
+        .unwrap();
+
+        let files = |files: &[&str]| {
+            files
+                .iter()
+                .map(|file| file.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(triggers.filters_paths());
+        assert!(triggers.allows_paths(&files(&["README.md", "src/lib.rs"])));
+        assert!(!triggers.allows_paths(&files(&["README.md", "docs/index.html"])));
+        assert!(!triggers.allows_paths(&files(&["src/docs/notes.md"])));
+        assert!(triggers.allows_paths(&[]));
+        assert!(!TriggerConfig::default().filters_paths());
+    }
+
+    #[test]
+    fn test_parse_ref() {
+        assert_eq!(
+            GitRef::parse("refs/heads/release/1.0"),
+            Some(GitRef::Branch("release/1.0".to_owned()))
+        );
+        assert_eq!(
+            GitRef::parse("refs/tags/v1.0"),
+            Some(GitRef::Tag("v1.0".to_owned()))
+        );
+        assert_eq!(GitRef::parse("refs/pull/1/head"), None);
+    }
+
+    #[test]
+    fn test_object_name() {
+        assert!(is_object_name("0d1a26e"));
+        assert!(is_object_name("0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c"));
+        assert!(!is_object_name("0d1"));
+        assert!(!is_object_name(
+            "--upload-pack=touch /tmp/x; git-upload-pack"
+        ));
+        assert!(!is_object_name("main"));
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+
+use actix_web::web::{self, Data};
+use actix_web::{post, HttpResponse};
+use serde::Deserialize;
+
+use super::{
+    dequeue_deleted_ref, parse, queue_pull_request, queue_push, receive, Outcome, Provider,
+    PullRequest,
+};
+use crate::queue::BuildQueue;
+use crate::routes::DbPool;
+use crate::triggers::{GitRef, PushEvent};
+use crate::verify_authentication_header;
+
+/// Handles Bitbucket Cloud's and Bitbucket Server's webhooks, dispatching on their X-Event-Key header
+/// URL is <host>:<port>/api/v1/webhook/bitbucket
+#[post("/api/v1/webhook/bitbucket")]
+pub(crate) async fn bitbucket_webhook(
+    request: web::HttpRequest,
+    body: web::Bytes,
+    pool: Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<HttpResponse> {
+    receive::<Bitbucket>(request, body, pool, queue).await
+}
+
+/// Bitbucket Cloud or Server, whose deliveries are authenticated by the hmac signature in X-Hub-Signature
+struct Bitbucket;
+
+impl Provider for Bitbucket {
+    const FALLBACK_SECRET: &'static str = "BITBUCKET_SECRET";
+
+    fn event(headers: &HashMap<String, String>) -> &str {
+        headers.get("x-event-key").map(String::as_str).unwrap_or("")
+    }
+
+    /// Cloud and Server name the delivery id differently
+    fn delivery_id(headers: &HashMap<String, String>) -> Option<&String> {
+        headers
+            .get("x-request-uuid")
+            .or_else(|| headers.get("x-request-id"))
+    }
+
+    /// Server's pull request events name the repository they're to as the target of the pull request,
+    /// which is built even if the payload has a top-level repository too
+    fn project(event: &str, body: &[u8]) -> Option<String> {
+        if event.starts_with("pr:") {
+            serde_json::from_slice::<ServerPullRequestTargetEventData>(body)
+                .ok()
+                .map(|event| event.pull_request.to_ref.repository.name())
+        } else {
+            serde_json::from_slice::<RepositoryEventData>(body)
+                .ok()
+                .map(|event| event.repository.name())
+        }
+    }
+
+    fn verify(
+        headers: &HashMap<String, String>,
+        body: &[u8],
+        secret: Option<String>,
+    ) -> Result<(), HttpResponse> {
+        verify_authentication_header(headers, body, secret)
+    }
+
+    /// Handles an event according to its type, from either Bitbucket Cloud or Server
+    async fn handle(
+        event: &str,
+        body: &[u8],
+        pool: &Data<DbPool>,
+        queue: Data<BuildQueue>,
+    ) -> actix_web::Result<Outcome> {
+        match event {
+            "diagnostics:ping" => Ok(Outcome::Handled("pong".to_owned())),
+            "repo:push" => {
+                let event: CloudPushEventData = parse(body)?;
+                let changes = event.push.changes.into_iter().filter_map(|change| {
+                    let (git_ref, after) = match (change.new, change.old.as_ref()) {
+                        (Some(new), _) => (new.git_ref()?, Some(new.target.hash)),
+                        (None, Some(old)) => (old.git_ref()?, None),
+                        (None, None) => return None,
+                    };
+
+                    Some(RefChange {
+                        git_ref,
+                        before: change.old.map(|old| old.target.hash),
+                        after,
+                    })
+                });
+
+                push(changes.collect(), event.repository.name(), pool, queue).await
+            }
+            "repo:refs_changed" => {
+                let event: ServerPushEventData = parse(body)?;
+                let changes = event.changes.into_iter().filter_map(|change| {
+                    let deleted = change.change_type == "DELETE";
+                    Some(RefChange {
+                        git_ref: GitRef::parse(&change.ref_id)?,
+                        before: Some(change.from_hash),
+                        after: if deleted { None } else { Some(change.to_hash) },
+                    })
+                });
+
+                push(changes.collect(), event.repository.name(), pool, queue).await
+            }
+            "pullrequest:created" | "pullrequest:updated" => {
+                let event: CloudPullRequestEventData = parse(body)?;
+                let source = event.pullrequest.source;
+                let destination = event.pullrequest.destination;
+                let pull_request = PullRequest {
+                    number: event.pullrequest.id,
+                    fork: source.repository.full_name.is_none()
+                        || source.repository.full_name != destination.repository.full_name,
+                    branch: source.branch.name,
+                    sha: source.commit.hash,
+                };
+
+                pull_request_opened(pull_request, event.repository.name(), pool, queue).await
+            }
+            "pr:opened" | "pr:from_ref_updated" => {
+                let event: ServerPullRequestEventData = parse(body)?;
+                let from_ref = event.pull_request.from_ref;
+                let to_ref = &event.pull_request.to_ref;
+                let pull_request = PullRequest {
+                    number: event.pull_request.id,
+                    fork: from_ref.repository.id.is_none()
+                        || from_ref.repository.id != to_ref.repository.id,
+                    branch: from_ref.display_id,
+                    sha: from_ref.latest_commit,
+                };
+                let project = event.pull_request.to_ref.repository.name();
+
+                pull_request_opened(pull_request, project, pool, queue).await
+            }
+            _ => Ok(Outcome::Ignored("Event ignored")),
+        }
+    }
+}
+
+/// A branch or tag changed by a push, as both Bitbucket Cloud and Server describe them
+struct RefChange {
+    git_ref: GitRef,
+    /// SHA the ref pointed at before the push, None if it was created
+    before: Option<String>,
+    /// SHA the ref points at after the push, None if it was deleted
+    after: Option<String>,
+}
+
+/// Handles pushes, building the commit each changed branch or tag now points at
+/// Deleted branches and tags have any queued builds of them dropped instead
+/// A push can change several refs, but only the first build queued is reported
+async fn push(
+    changes: Vec<RefChange>,
+    project: String,
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<Outcome> {
+    let mut outcome = Outcome::Ignored("Push changed no branches or tags, ignored");
+
+    for change in changes {
+        let changed = match change.after {
+            None => {
+                dequeue_deleted_ref(pool, queue.clone(), project.clone(), change.git_ref).await?
+            }
+            Some(after) => {
+                println!("Received push of {:?} to '{}'", change.git_ref, project);
+
+                let push = PushEvent {
+                    git_ref: change.git_ref,
+                    before: change.before,
+                    after: Some(after),
+                    changed_files: None,
+                    author: None,
+                };
+                queue_push(pool, queue.clone(), project.clone(), move |_| Some(push)).await?
+            }
+        };
+
+        if !matches!(outcome, Outcome::Queued(_)) {
+            outcome = changed;
+        }
+    }
+
+    Ok(outcome)
+}
+
+/// Handles pull requests being opened or updated, building their head
+async fn pull_request_opened(
+    pull_request: PullRequest,
+    project: String,
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<Outcome> {
+    println!(
+        "Received pull request #{} for '{}'",
+        pull_request.number, project
+    );
+
+    queue_pull_request(pool, queue, project, pull_request).await
+}
+
+/// Represents a repository taken from bitbucket webhook
+/// Server gives its slug, whereas Cloud only gives its full name, 'workspace/slug'
+#[derive(Debug, Deserialize)]
+struct RepositoryData {
+    slug: Option<String>,
+    full_name: Option<String>,
+    /// Only given by Server
+    id: Option<i64>,
+}
+
+impl RepositoryData {
+    /// The name of the project in drovah, the repository's slug
+    fn name(&self) -> String {
+        match (&self.slug, &self.full_name) {
+            (Some(slug), _) => slug.clone(),
+            (None, Some(full_name)) => full_name.rsplit('/').next().unwrap_or_default().to_owned(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+/// Represents any event taken from bitbucket webhook, other than Server's pull request events, for the repository it's about
+#[derive(Debug, Deserialize)]
+struct RepositoryEventData {
+    repository: RepositoryData,
+}
+
+/// Represents a repo:push event taken from bitbucket cloud webhook
+#[derive(Debug, Deserialize)]
+struct CloudPushEventData {
+    push: CloudPushData,
+    repository: RepositoryData,
+}
+
+/// Represents a push taken from bitbucket cloud webhook
+#[derive(Debug, Deserialize)]
+struct CloudPushData {
+    changes: Vec<CloudChangeData>,
+}
+
+/// Represents a change to a ref taken from bitbucket cloud webhook
+/// 'new' is null when the ref was deleted, and 'old' when it was created
+#[derive(Debug, Deserialize)]
+struct CloudChangeData {
+    new: Option<CloudRefData>,
+    old: Option<CloudRefData>,
+}
+
+/// Represents a branch or tag taken from bitbucket cloud webhook
+#[derive(Debug, Deserialize)]
+struct CloudRefData {
+    #[serde(rename = "type")]
+    ref_type: String,
+    name: String,
+    target: CloudCommitData,
+}
+
+impl CloudRefData {
+    /// The ref, None if it isn't a branch or tag
+    fn git_ref(&self) -> Option<GitRef> {
+        match self.ref_type.as_str() {
+            "branch" => Some(GitRef::Branch(self.name.clone())),
+            "tag" => Some(GitRef::Tag(self.name.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// Represents a commit taken from bitbucket cloud webhook
+#[derive(Debug, Deserialize)]
+struct CloudCommitData {
+    hash: String,
+}
+
+/// Represents a pullrequest event taken from bitbucket cloud webhook
+#[derive(Debug, Deserialize)]
+struct CloudPullRequestEventData {
+    pullrequest: CloudPullRequestData,
+    repository: RepositoryData,
+}
+
+/// Represents a pull request taken from bitbucket cloud webhook
+#[derive(Debug, Deserialize)]
+struct CloudPullRequestData {
+    id: i32,
+    source: CloudPullRequestSourceData,
+    destination: CloudPullRequestDestinationData,
+}
+
+/// Represents the head of a pull request taken from bitbucket cloud webhook
+#[derive(Debug, Deserialize)]
+struct CloudPullRequestSourceData {
+    branch: CloudBranchData,
+    /// Cloud only gives an abbreviated hash, which is resolved once fetched
+    commit: CloudCommitData,
+    /// Repository the pull request is from, which for forks isn't the one it's to
+    repository: RepositoryData,
+}
+
+/// Represents what a pull request is to be merged into, taken from bitbucket cloud webhook
+#[derive(Debug, Deserialize)]
+struct CloudPullRequestDestinationData {
+    repository: RepositoryData,
+}
+
+/// Represents a branch taken from bitbucket cloud webhook
+#[derive(Debug, Deserialize)]
+struct CloudBranchData {
+    name: String,
+}
+
+/// Represents a repo:refs_changed event taken from bitbucket server webhook
+#[derive(Debug, Deserialize)]
+struct ServerPushEventData {
+    changes: Vec<ServerChangeData>,
+    repository: RepositoryData,
+}
+
+/// Represents a change to a ref taken from bitbucket server webhook
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerChangeData {
+    /// Full ref, e.g. 'refs/heads/main'
+    ref_id: String,
+    from_hash: String,
+    to_hash: String,
+    /// One of ADD, UPDATE or DELETE
+    #[serde(rename = "type")]
+    change_type: String,
+}
+
+/// Represents a pull request event taken from bitbucket server webhook
+#[derive(Debug, Deserialize)]
+struct ServerPullRequestEventData {
+    #[serde(rename = "pullRequest")]
+    pull_request: ServerPullRequestData,
+}
+
+/// Represents a pull request taken from bitbucket server webhook
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerPullRequestData {
+    id: i32,
+    from_ref: ServerPullRequestRefData,
+    to_ref: ServerTargetRefData,
+}
+
+/// Represents the head of a pull request taken from bitbucket server webhook
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerPullRequestRefData {
+    /// Name of the branch the pull request is from
+    display_id: String,
+    latest_commit: String,
+    /// Repository the pull request is from, which for forks isn't the one it's to
+    repository: RepositoryData,
+}
+
+/// Represents what a pull request is to be merged into, taken from bitbucket server webhook
+#[derive(Debug, Deserialize)]
+struct ServerTargetRefData {
+    repository: RepositoryData,
+}
+
+/// Represents any pull request event taken from bitbucket server webhook, for the repository it's to
+#[derive(Debug, Deserialize)]
+struct ServerPullRequestTargetEventData {
+    #[serde(rename = "pullRequest")]
+    pull_request: ServerPullRequestTargetData,
+}
+
+/// Represents just the target of a pull request taken from bitbucket server webhook
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerPullRequestTargetData {
+    to_ref: ServerTargetRefData,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repository_name() {
+        let cloud = br#"{ "repository": { "name": "Drovah", "full_name": "team/drovah" } }"#;
+        assert_eq!(
+            Bitbucket::project("repo:push", cloud).as_deref(),
+            Some("drovah")
+        );
+
+        // The top-level repository of a pull request event isn't what's built, so mustn't pick the secret
+        let server = br#"{
+            "repository": { "slug": "other" },
+            "pullRequest": {
+                "id": 1,
+                "toRef": { "repository": { "slug": "drovah", "name": "Drovah" } }
+            }
+        }"#;
+        assert_eq!(
+            Bitbucket::project("pr:opened", server).as_deref(),
+            Some("drovah")
+        );
+
+        let push: CloudPushEventData = parse(
+            br#"{
+                "push": { "changes": [{
+                    "new": { "type": "tag", "name": "v1.0", "target": { "hash": "0d1a26e" } },
+                    "old": null
+                }] },
+                "repository": { "full_name": "team/drovah" }
+            }"#,
+        )
+        .unwrap();
+        let new = push.push.changes[0].new.as_ref().unwrap();
+        assert_eq!(new.git_ref(), Some(GitRef::Tag("v1.0".to_owned())));
+    }
+}
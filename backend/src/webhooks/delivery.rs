@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+use chrono::{NaiveDateTime, Utc};
+use diesel::dsl::Eq;
+use diesel::result::DatabaseErrorKind;
+use diesel::result::Error::DatabaseError;
+use diesel::{insert_into, prelude::*, update};
+
+use super::Outcome;
+use crate::routes::{with_database, DbPool};
+use crate::schema::webhook_deliveries::dsl as delivery;
+
+/// Headers which carry credentials, and so are never stored
+const SECRET_HEADERS: &[&str] = &["authorization", "x-gitlab-token"];
+
+/// The columns a delivery is recorded with
+type DeliveryValues = (
+    Eq<delivery::delivery_id, Option<String>>,
+    Eq<delivery::event, String>,
+    Eq<delivery::headers, String>,
+    Eq<delivery::body, String>,
+    Eq<delivery::verified, bool>,
+    Eq<delivery::processed, bool>,
+    Eq<delivery::outcome, String>,
+    Eq<delivery::build_id, Option<i32>>,
+    Eq<delivery::received_at, NaiveDateTime>,
+);
+
+/// A webhook request, recorded in 'webhook_deliveries' along with what came of it
+pub(crate) struct Delivery {
+    /// The id the sender gave the delivery, if any
+    delivery_id: Option<String>,
+    event: String,
+    headers: String,
+    body: String,
+    /// Whether the delivery's id has been claimed by this delivery
+    claimed: bool,
+}
+
+impl Delivery {
+    pub(crate) fn new(
+        delivery_id: Option<&String>,
+        event: &str,
+        headers: &HashMap<String, String>,
+        body: &[u8],
+    ) -> Self {
+        let headers: HashMap<&String, &String> = headers
+            .iter()
+            .filter(|(name, _)| !SECRET_HEADERS.contains(&name.as_str()))
+            .collect();
+
+        Delivery {
+            delivery_id: delivery_id.cloned(),
+            event: event.to_owned(),
+            headers: serde_json::to_string(&headers).unwrap_or_default(),
+            body: String::from_utf8_lossy(body).into_owned(),
+            claimed: false,
+        }
+    }
+
+    /// Checks whether the delivery should be handled, recording it if not
+    /// Deliveries with an id are claimed before they're handled, so only one of several redeliveries
+    /// arriving at once is handled
+    /// Returns the response for deliveries which failed authentication, or have been claimed already
+    pub(crate) async fn screen(
+        &mut self,
+        pool: &Data<DbPool>,
+        verified: Result<(), HttpResponse>,
+    ) -> actix_web::Result<Option<HttpResponse>> {
+        if let Err(response) = verified {
+            self.record(pool, false, false, "Authentication failed".to_owned(), None)
+                .await;
+            return Ok(Some(response));
+        }
+
+        let delivery_id = match &self.delivery_id {
+            Some(delivery_id) => delivery_id.clone(),
+            None => return Ok(None),
+        };
+
+        let values = self.values(true, false, "Processing".to_owned(), None);
+        let claimed = with_database(pool, move |database| {
+            insert_into(delivery::webhook_deliveries)
+                .values((values, delivery::claimed_delivery_id.eq(delivery_id)))
+                .execute(database)
+        })
+        .await?;
+
+        match claimed {
+            Ok(_) => {
+                self.claimed = true;
+                return Ok(None);
+            }
+            Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {}
+            Err(e) => {
+                eprintln!("Error claiming webhook delivery: {}", e);
+                return Err(actix_web::error::ErrorInternalServerError(
+                    "Error claiming delivery",
+                ));
+            }
+        }
+
+        self.record(pool, true, false, "Redelivery ignored".to_owned(), None)
+            .await;
+        Ok(Some(
+            HttpResponse::Ok().body("Delivery already processed, ignored"),
+        ))
+    }
+
+    /// Records what came of handling the delivery, returning the response for it
+    pub(crate) async fn finish(
+        self,
+        pool: &Data<DbPool>,
+        handled: actix_web::Result<Outcome>,
+    ) -> actix_web::Result<HttpResponse> {
+        match handled {
+            Ok(outcome) => {
+                let processed = !matches!(outcome, Outcome::Failed);
+                let build_id = match &outcome {
+                    Outcome::Queued(build_id) => Some(*build_id),
+                    _ => None,
+                };
+
+                self.record(pool, true, processed, outcome.to_string(), build_id)
+                    .await;
+                Ok(outcome.into_response())
+            }
+            Err(e) => {
+                self.record(pool, true, false, format!("Error: {}", e), None)
+                    .await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Stores the delivery, reporting rather than failing on errors as the request has been dealt with
+    /// A claimed delivery has its row updated instead, giving up the claim if it wasn't processed so it can be redelivered
+    async fn record(
+        &self,
+        pool: &Data<DbPool>,
+        verified: bool,
+        processed: bool,
+        outcome: String,
+        build_id: Option<i32>,
+    ) {
+        let recorded = match (&self.delivery_id, self.claimed) {
+            (Some(delivery_id), true) => {
+                let claimed = delivery::webhook_deliveries
+                    .filter(delivery::claimed_delivery_id.eq(delivery_id.clone()));
+                let claim = Some(delivery_id.clone()).filter(|_| processed);
+
+                with_database(pool, move |database| {
+                    update(claimed)
+                        .set((
+                            delivery::processed.eq(processed),
+                            delivery::outcome.eq(outcome),
+                            delivery::build_id.eq(build_id),
+                            delivery::claimed_delivery_id.eq(claim),
+                        ))
+                        .execute(database)
+                })
+                .await
+            }
+            _ => {
+                let values = self.values(verified, processed, outcome, build_id);
+
+                with_database(pool, move |database| {
+                    insert_into(delivery::webhook_deliveries)
+                        .values(values)
+                        .execute(database)
+                })
+                .await
+            }
+        };
+
+        match recorded {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("Error recording webhook delivery: {}", e),
+            Err(e) => eprintln!("Error recording webhook delivery: {}", e),
+        }
+    }
+
+    /// The row recording the delivery
+    fn values(
+        &self,
+        verified: bool,
+        processed: bool,
+        outcome: String,
+        build_id: Option<i32>,
+    ) -> DeliveryValues {
+        (
+            delivery::delivery_id.eq(self.delivery_id.clone()),
+            delivery::event.eq(self.event.clone()),
+            delivery::headers.eq(self.headers.clone()),
+            delivery::body.eq(self.body.clone()),
+            delivery::verified.eq(verified),
+            delivery::processed.eq(processed),
+            delivery::outcome.eq(outcome),
+            delivery::build_id.eq(build_id),
+            delivery::received_at.eq(Utc::now().naive_utc()),
+        )
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use actix_web::web::{self, Data};
+use actix_web::{post, HttpResponse};
+use serde::Deserialize;
+
+use super::{
+    changed_files, delete_event, parse, pull_request_event, queue_push, receive, CommitData,
+    Outcome, Provider, RepositoryData, RepositoryEventData,
+};
+use crate::queue::BuildQueue;
+use crate::routes::DbPool;
+use crate::triggers::{is_null_sha, GitRef, PushEvent};
+use crate::verify_sha256_header;
+
+/// Handles Gitea's (and so Forgejo's) webhook, dispatching on its X-Gitea-Event header
+/// URL is <host>:<port>/api/v1/webhook/gitea
+#[post("/api/v1/webhook/gitea")]
+pub(crate) async fn gitea_webhook(
+    request: web::HttpRequest,
+    body: web::Bytes,
+    pool: Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<HttpResponse> {
+    receive::<Gitea>(request, body, pool, queue).await
+}
+
+/// Gitea, whose deliveries are authenticated by the hmac sha256 signature in X-Gitea-Signature
+/// Forgejo's own X-Forgejo-* headers are used when the X-Gitea-* ones are missing
+struct Gitea;
+
+impl Gitea {
+    /// The name of the given X-Gitea-* header, or its X-Forgejo-* counterpart if it wasn't sent
+    fn header_name(headers: &HashMap<String, String>, name: &str) -> String {
+        let gitea = format!("x-gitea-{}", name);
+        if headers.contains_key(&gitea) {
+            gitea
+        } else {
+            format!("x-forgejo-{}", name)
+        }
+    }
+}
+
+impl Provider for Gitea {
+    const FALLBACK_SECRET: &'static str = "GITEA_SECRET";
+
+    fn event(headers: &HashMap<String, String>) -> &str {
+        headers
+            .get(&Gitea::header_name(headers, "event"))
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+
+    fn delivery_id(headers: &HashMap<String, String>) -> Option<&String> {
+        headers.get(&Gitea::header_name(headers, "delivery"))
+    }
+
+    fn project(_event: &str, body: &[u8]) -> Option<String> {
+        serde_json::from_slice::<RepositoryEventData>(body)
+            .ok()
+            .map(|event| event.repository.name)
+    }
+
+    fn verify(
+        headers: &HashMap<String, String>,
+        body: &[u8],
+        secret: Option<String>,
+    ) -> Result<(), HttpResponse> {
+        verify_sha256_header(
+            headers,
+            &Gitea::header_name(headers, "signature"),
+            body,
+            secret,
+        )
+    }
+
+    /// Gitea doesn't send a push for deletions, so delete events are all that's heard of them
+    async fn handle(
+        event: &str,
+        body: &[u8],
+        pool: &Data<DbPool>,
+        queue: Data<BuildQueue>,
+    ) -> actix_web::Result<Outcome> {
+        match event {
+            "push" => push(body, pool, queue).await,
+            "pull_request" => pull_request_event(body, pool, queue).await,
+            "delete" => delete_event(body, pool, queue).await,
+            _ => Ok(Outcome::Ignored("Event ignored")),
+        }
+    }
+}
+
+/// Handles push events, building the pushed commit
+async fn push(
+    body: &[u8],
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<Outcome> {
+    let event: PushEventData = parse(body)?;
+
+    if event.after.as_deref().is_some_and(is_null_sha) {
+        return Ok(Outcome::Ignored("Push deleted the ref, ignored"));
+    }
+
+    let project = event.repository.name.clone();
+
+    println!("Received push of {} to '{}'", event.git_ref, project);
+
+    queue_push(pool, queue, project, move |_| event.push_event()).await
+}
+
+/// Represents a push event taken from gitea webhook
+#[derive(Debug, Deserialize)]
+struct PushEventData {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    /// SHA of the commit the ref pointed at before the push
+    before: Option<String>,
+    /// SHA of the commit the ref points at after the push
+    after: Option<String>,
+    #[serde(default)]
+    commits: Vec<CommitData>,
+    total_commits: Option<usize>,
+    repository: RepositoryData,
+}
+
+impl PushEventData {
+    /// The push described by the payload, None if the ref isn't a branch or tag
+    fn push_event(&self) -> Option<PushEvent> {
+        Some(PushEvent {
+            git_ref: GitRef::parse(&self.git_ref)?,
+            before: self.before.clone(),
+            after: self.after.clone(),
+            changed_files: self.changed_files(),
+            author: None,
+        })
+    }
+
+    /// Files touched by the pushed commits
+    /// Gitea only lists a limited number of commits, so pushes with more (or none) have to be diffed instead
+    fn changed_files(&self) -> Option<Vec<String>> {
+        let listed_all = self.total_commits == Some(self.commits.len());
+        if self.commits.is_empty() || !listed_all {
+            return None;
+        }
+
+        Some(changed_files(&self.commits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_event() {
+        let push: PushEventData = parse(
+            br#"{
+                "ref": "refs/heads/main",
+                "before": "0000000000000000000000000000000000000000",
+                "after": "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c",
+                "total_commits": 2,
+                "commits": [
+                    { "id": "a", "added": ["src/new.rs"], "modified": [], "removed": [] }
+                ],
+                "repository": { "name": "drovah" }
+            }"#,
+        )
+        .unwrap();
+
+        let push = push.push_event().unwrap();
+        assert_eq!(push.git_ref, GitRef::Branch("main".to_owned()));
+        assert!(push.changed_files.is_none());
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use actix_web::web::{self, Data};
+use actix_web::{post, HttpResponse};
+use serde::Deserialize;
+
+use super::{
+    changed_files, delete_event, parse, pull_request_event, queue_push, receive, CommitData,
+    Outcome, Provider, RefEventData, RepositoryData, RepositoryEventData,
+};
+use crate::queue::BuildQueue;
+use crate::routes::DbPool;
+use crate::triggers::{is_null_sha, GitRef, PushEvent};
+use crate::verify_authentication_header;
+
+/// Handles webhook
+/// targeted towards GitHub's webhook, dispatching on its X-GitHub-Event header
+/// however, would support others as long as they adhere to format, as payloads without the header are taken to be pushes
+/// Every delivery is recorded, and redeliveries of ones already processed are ignored
+/// URL is <host>:<port>/api/v1/webhook
+#[post("/api/v1/webhook")]
+pub(crate) async fn github_webhook(
+    request: web::HttpRequest,
+    body: web::Bytes,
+    pool: Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<HttpResponse> {
+    receive::<GitHub>(request, body, pool, queue).await
+}
+
+/// GitHub, whose deliveries are authenticated by the hmac signature in X-Hub-Signature-256 or X-Hub-Signature
+struct GitHub;
+
+impl Provider for GitHub {
+    const FALLBACK_SECRET: &'static str = "GITHUB_SECRET";
+
+    fn event(headers: &HashMap<String, String>) -> &str {
+        headers
+            .get("x-github-event")
+            .map(String::as_str)
+            .unwrap_or("push")
+    }
+
+    fn delivery_id(headers: &HashMap<String, String>) -> Option<&String> {
+        headers.get("x-github-delivery")
+    }
+
+    fn project(_event: &str, body: &[u8]) -> Option<String> {
+        serde_json::from_slice::<RepositoryEventData>(body)
+            .ok()
+            .map(|event| event.repository.name)
+    }
+
+    fn verify(
+        headers: &HashMap<String, String>,
+        body: &[u8],
+        secret: Option<String>,
+    ) -> Result<(), HttpResponse> {
+        verify_authentication_header(headers, body, secret)
+    }
+
+    async fn handle(
+        event: &str,
+        body: &[u8],
+        pool: &Data<DbPool>,
+        queue: Data<BuildQueue>,
+    ) -> actix_web::Result<Outcome> {
+        match event {
+            "ping" => Ok(Outcome::Handled("pong".to_owned())),
+            "push" => push(body, pool, queue).await,
+            "pull_request" => pull_request_event(body, pool, queue).await,
+            "create" => create(body),
+            "delete" => delete_event(body, pool, queue).await,
+            _ => Ok(Outcome::Ignored("Event ignored")),
+        }
+    }
+}
+
+/// Handles push events, building the pushed commit
+async fn push(
+    body: &[u8],
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<Outcome> {
+    let webhookdata: WebhookData = parse(body)?;
+
+    if webhookdata.is_deletion() {
+        return Ok(Outcome::Ignored("Push deleted the ref, ignored"));
+    }
+
+    let project = webhookdata.repository.name.clone();
+    println!(
+        "Received push of {} to '{}'",
+        webhookdata.git_ref.as_deref().unwrap_or("unknown ref"),
+        project
+    );
+
+    queue_push(pool, queue, project, move |default_branch| {
+        webhookdata.push_event(default_branch)
+    })
+    .await
+}
+
+/// Handles create events
+/// Creating a branch or tag also sends a push event, which is what gets built
+fn create(body: &[u8]) -> actix_web::Result<Outcome> {
+    let _event: RefEventData = parse(body)?;
+    Ok(Outcome::Ignored(
+        "Builds are started by the push event, ignored",
+    ))
+}
+
+/// Represents data taken from github webhook
+#[derive(Debug, Deserialize)]
+struct WebhookData {
+    repository: RepositoryData,
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    /// SHA of the commit the ref pointed at before the push
+    before: Option<String>,
+    /// SHA of the commit the ref now points at
+    after: Option<String>,
+    head_commit: Option<CommitData>,
+    #[serde(default)]
+    commits: Vec<CommitData>,
+    /// Number of commits pushed, which not every payload gives
+    size: Option<usize>,
+}
+
+impl WebhookData {
+    /// The commit to build, if the payload names one
+    fn commit_sha(&self) -> Option<&str> {
+        self.after
+            .as_deref()
+            .or_else(|| self.head_commit.as_ref().map(|commit| commit.id.as_str()))
+    }
+
+    /// The push described by the payload, None if the ref isn't a branch or tag
+    /// Payloads without a ref are taken to be for the project's default branch
+    fn push_event(&self, default_branch: &str) -> Option<PushEvent> {
+        let git_ref = match self.git_ref.as_deref() {
+            Some(git_ref) => GitRef::parse(git_ref)?,
+            None => GitRef::Branch(default_branch.to_owned()),
+        };
+
+        Some(PushEvent {
+            git_ref,
+            before: self.before.clone(),
+            after: self.commit_sha().map(str::to_owned),
+            changed_files: self.changed_files(),
+            author: None,
+        })
+    }
+
+    /// Files touched by the pushed commits
+    /// GitHub lists at most 2048 commits, so pushes with more (or none) have to be diffed instead
+    fn changed_files(&self) -> Option<Vec<String>> {
+        let listed_all = match self.size {
+            Some(size) => self.commits.len() == size,
+            None => self.commits.len() < 2048,
+        };
+        if self.commits.is_empty() || !listed_all {
+            return None;
+        }
+
+        Some(changed_files(&self.commits))
+    }
+
+    /// Whether the push deleted the ref
+    fn is_deletion(&self) -> bool {
+        self.after.as_deref().is_some_and(is_null_sha)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_event() {
+        let webhookdata: WebhookData = serde_json::from_str(
+            r#"{
+                "ref": "refs/heads/release/1.0",
+                "before": "0000000000000000000000000000000000000000",
+                "after": "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c",
+                "repository": { "name": "drovah" },
+                "commits": [
+                    { "id": "a", "added": ["src/new.rs"], "modified": ["README.md"], "removed": [] },
+                    { "id": "b", "added": [], "modified": ["README.md"], "removed": ["src/old.rs"] }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let push = webhookdata.push_event("master").unwrap();
+        assert_eq!(push.git_ref, GitRef::Branch("release/1.0".to_owned()));
+        assert_eq!(
+            push.after.as_deref(),
+            Some("0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c")
+        );
+        assert_eq!(
+            push.changed_files.unwrap(),
+            vec!["README.md", "src/new.rs", "src/old.rs"]
+        );
+        assert!(!webhookdata.is_deletion());
+
+        let truncated: WebhookData = parse(
+            br#"{
+                "repository": { "name": "drovah" },
+                "size": 3000,
+                "commits": [{ "id": "a", "added": ["src/new.rs"] }]
+            }"#,
+        )
+        .unwrap();
+        assert!(truncated.changed_files().is_none());
+
+        let legacy: WebhookData = parse(br#"{ "repository": { "name": "drovah" } }"#).unwrap();
+        let push = legacy.push_event("master").unwrap();
+        assert_eq!(push.git_ref, GitRef::Branch("master".to_owned()));
+        assert!(push.changed_files.is_none());
+
+        assert!(parse::<WebhookData>(b"zen").is_err());
+    }
+}
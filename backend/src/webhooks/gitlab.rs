@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use actix_web::web::{self, Data};
+use actix_web::{post, HttpResponse};
+use serde::Deserialize;
+
+use super::{
+    changed_files, dequeue_deleted_ref, parse, queue_pull_request, queue_push, receive, CommitData,
+    Outcome, Provider, PullRequest,
+};
+use crate::queue::BuildQueue;
+use crate::routes::DbPool;
+use crate::triggers::{is_null_sha, GitRef, PushEvent};
+use crate::verify_token_header;
+
+/// Handles GitLab's webhook, dispatching on its X-Gitlab-Event header
+/// URL is <host>:<port>/api/v1/webhook/gitlab
+#[post("/api/v1/webhook/gitlab")]
+pub(crate) async fn gitlab_webhook(
+    request: web::HttpRequest,
+    body: web::Bytes,
+    pool: Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<HttpResponse> {
+    receive::<GitLab>(request, body, pool, queue).await
+}
+
+/// GitLab, whose deliveries are authenticated by the secret token it sends in X-Gitlab-Token
+struct GitLab;
+
+impl Provider for GitLab {
+    const FALLBACK_SECRET: &'static str = "GITLAB_SECRET";
+
+    fn event(headers: &HashMap<String, String>) -> &str {
+        headers
+            .get("x-gitlab-event")
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+
+    fn delivery_id(headers: &HashMap<String, String>) -> Option<&String> {
+        headers.get("x-gitlab-event-uuid")
+    }
+
+    fn project(_event: &str, body: &[u8]) -> Option<String> {
+        serde_json::from_slice::<ProjectEventData>(body)
+            .ok()
+            .map(|event| event.project.name())
+    }
+
+    fn verify(
+        headers: &HashMap<String, String>,
+        _body: &[u8],
+        secret: Option<String>,
+    ) -> Result<(), HttpResponse> {
+        verify_token_header(headers, "x-gitlab-token", secret)
+    }
+
+    async fn handle(
+        event: &str,
+        body: &[u8],
+        pool: &Data<DbPool>,
+        queue: Data<BuildQueue>,
+    ) -> actix_web::Result<Outcome> {
+        match event {
+            "Push Hook" | "Tag Push Hook" => push(body, pool, queue).await,
+            "Merge Request Hook" => merge_request(body, pool, queue).await,
+            _ => Ok(Outcome::Ignored("Event ignored")),
+        }
+    }
+}
+
+/// Handles push and tag push events, building the pushed commit
+/// GitLab has no separate event for deleting a branch or tag, so deletions drop any queued builds of it
+async fn push(
+    body: &[u8],
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<Outcome> {
+    let event: PushEventData = parse(body)?;
+    let project = event.project.name();
+
+    if event.is_deletion() {
+        return match GitRef::parse(&event.git_ref) {
+            Some(git_ref) => dequeue_deleted_ref(pool, queue, project, git_ref).await,
+            None => Ok(Outcome::Ignored("Ref is not a branch or tag, ignored")),
+        };
+    }
+
+    println!("Received push of {} to '{}'", event.git_ref, project);
+
+    queue_push(pool, queue, project, move |_| event.push_event()).await
+}
+
+/// Handles merge request events, building the head of merge requests as they're opened or updated
+async fn merge_request(
+    body: &[u8],
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<Outcome> {
+    let event: MergeRequestEventData = parse(body)?;
+    let merge_request = event.object_attributes;
+
+    if !merge_request.needs_build() {
+        return Ok(Outcome::Ignored("Merge request event ignored"));
+    }
+
+    let project = event.project.name();
+    println!(
+        "Received merge request !{} ({}) for '{}'",
+        merge_request.iid,
+        merge_request.action.as_deref().unwrap_or("unknown"),
+        project
+    );
+
+    let pull_request = PullRequest {
+        number: merge_request.iid,
+        fork: merge_request.source_project_id != merge_request.target_project_id,
+        branch: merge_request.source_branch,
+        sha: merge_request.last_commit.id,
+    };
+
+    queue_pull_request(pool, queue, project, pull_request).await
+}
+
+/// Represents a push or tag push event taken from gitlab webhook
+#[derive(Debug, Deserialize)]
+struct PushEventData {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    /// SHA of the commit the ref pointed at before the push
+    before: Option<String>,
+    /// SHA the ref points at after the push, which for annotated tags is the tag rather than a commit
+    after: Option<String>,
+    /// SHA of the commit to build, null when the ref was deleted
+    checkout_sha: Option<String>,
+    #[serde(default)]
+    commits: Vec<CommitData>,
+    total_commits_count: Option<usize>,
+    project: ProjectData,
+}
+
+impl PushEventData {
+    /// The push described by the payload, None if the ref isn't a branch or tag
+    fn push_event(&self) -> Option<PushEvent> {
+        Some(PushEvent {
+            git_ref: GitRef::parse(&self.git_ref)?,
+            before: self.before.clone(),
+            after: self.checkout_sha.clone(),
+            changed_files: self.changed_files(),
+            author: None,
+        })
+    }
+
+    /// Files touched by the pushed commits
+    /// GitLab lists at most 20 commits, so pushes with more (or none) have to be diffed instead
+    fn changed_files(&self) -> Option<Vec<String>> {
+        let listed_all = self.total_commits_count == Some(self.commits.len());
+        if self.commits.is_empty() || !listed_all {
+            return None;
+        }
+
+        Some(changed_files(&self.commits))
+    }
+
+    /// Whether the push deleted the ref
+    fn is_deletion(&self) -> bool {
+        self.checkout_sha.is_none() || self.after.as_deref().is_some_and(is_null_sha)
+    }
+}
+
+/// Represents a merge request event taken from gitlab webhook
+#[derive(Debug, Deserialize)]
+struct MergeRequestEventData {
+    object_attributes: MergeRequestData,
+    project: ProjectData,
+}
+
+/// Represents a merge request taken from gitlab webhook
+#[derive(Debug, Deserialize)]
+struct MergeRequestData {
+    /// Number of the merge request within its project
+    iid: i32,
+    action: Option<String>,
+    /// Name of the branch the merge request is from
+    source_branch: String,
+    /// Project the merge request is from, which for forks isn't the one it's to
+    source_project_id: i64,
+    target_project_id: i64,
+    last_commit: LastCommitData,
+    /// Previous head of the merge request, only sent with updates which pushed new commits
+    oldrev: Option<String>,
+}
+
+impl MergeRequestData {
+    /// Whether the event changes what the merge request would merge, so it should be built
+    /// Updates which only change the title, description or the like aren't built
+    fn needs_build(&self) -> bool {
+        match self.action.as_deref() {
+            Some("open") | Some("reopen") => true,
+            Some("update") => self.oldrev.is_some(),
+            _ => false,
+        }
+    }
+}
+
+/// Represents the head of a merge request taken from gitlab webhook
+#[derive(Debug, Deserialize)]
+struct LastCommitData {
+    id: String,
+}
+
+/// Represents a project taken from gitlab webhook
+#[derive(Debug, Deserialize)]
+struct ProjectData {
+    path_with_namespace: String,
+}
+
+impl ProjectData {
+    /// The name of the project in drovah, the last part of its path, e.g. 'drovah' for 'group/drovah'
+    fn name(&self) -> String {
+        self.path_with_namespace
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_owned()
+    }
+}
+
+/// Represents any event taken from gitlab webhook, for the project it's about
+#[derive(Debug, Deserialize)]
+struct ProjectEventData {
+    project: ProjectData,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_event() {
+        let event: PushEventData = parse(
+            br#"{
+                "object_kind": "tag_push",
+                "ref": "refs/tags/v1.0",
+                "before": "0000000000000000000000000000000000000000",
+                "after": "82b3d5ae55f7080f1e6022629cdb57bfae7cccc7",
+                "checkout_sha": "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c",
+                "total_commits_count": 1,
+                "commits": [
+                    { "id": "a", "added": ["src/new.rs"], "modified": [], "removed": [] }
+                ],
+                "project": { "path_with_namespace": "tools/ci/drovah" }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(event.project.name(), "drovah");
+        assert!(!event.is_deletion());
+
+        let push = event.push_event().unwrap();
+        assert_eq!(push.git_ref, GitRef::Tag("v1.0".to_owned()));
+        assert_eq!(
+            push.after.as_deref(),
+            Some("0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c")
+        );
+        assert_eq!(push.changed_files.unwrap(), vec!["src/new.rs"]);
+
+        let deletion: PushEventData = parse(
+            br#"{
+                "ref": "refs/heads/feature",
+                "before": "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c",
+                "after": "0000000000000000000000000000000000000000",
+                "checkout_sha": null,
+                "total_commits_count": 0,
+                "project": { "path_with_namespace": "drovah" }
+            }"#,
+        )
+        .unwrap();
+        assert!(deletion.is_deletion());
+    }
+}
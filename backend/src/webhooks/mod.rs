@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::{env, fmt};
+
+use actix_web::web::{self, Data};
+use actix_web::HttpResponse;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::queue::{BuildQueue, BuildRequest};
+use crate::routes::{run_blocking, with_database, DbPool};
+use crate::triggers::{GitRef, PushEvent};
+use crate::{
+    get_default_branch, get_headers_hash_map, get_project, get_project_id, get_webhook_secret,
+};
+use delivery::Delivery;
+
+pub(crate) mod bitbucket;
+pub(crate) mod delivery;
+pub(crate) mod gitea;
+pub(crate) mod github;
+pub(crate) mod gitlab;
+pub(crate) mod trigger;
+
+/// What became of a webhook event
+pub(crate) enum Outcome {
+    /// A build was queued, with the given build id
+    Queued(i32),
+    /// The event didn't call for a build, for the given reason
+    Ignored(&'static str),
+    /// Something was done other than queueing a build, described by the message
+    Handled(String),
+    UnknownProject,
+    Failed,
+}
+
+impl Outcome {
+    pub(crate) fn into_response(self) -> HttpResponse {
+        match self {
+            Outcome::Queued(_) => HttpResponse::NoContent().finish(),
+            Outcome::Ignored(reason) => HttpResponse::Accepted().body(reason),
+            Outcome::Handled(message) => HttpResponse::Ok().body(message),
+            Outcome::UnknownProject => HttpResponse::NotAcceptable().body("Project doesn't exist"),
+            Outcome::Failed => HttpResponse::InternalServerError().finish(),
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Queued(build_id) => write!(f, "Queued build {}", build_id),
+            Outcome::Ignored(reason) => write!(f, "{}", reason),
+            Outcome::Handled(message) => write!(f, "{}", message),
+            Outcome::UnknownProject => write!(f, "Project doesn't exist"),
+            Outcome::Failed => write!(f, "Failed"),
+        }
+    }
+}
+
+/// A service sending webhooks, which 'receive' authenticates and records deliveries from
+pub(crate) trait Provider {
+    /// Environment variable holding the secret of projects without their own
+    const FALLBACK_SECRET: &'static str;
+
+    /// The type of event a delivery is of
+    fn event(headers: &HashMap<String, String>) -> &str;
+
+    /// The id the service gave a delivery, repeated when it's redelivered
+    fn delivery_id(headers: &HashMap<String, String>) -> Option<&String>;
+
+    /// The project a payload is about, if it can be read from it
+    /// Must be read from wherever 'handle' takes the project for that type of event, as it's whose secret is checked
+    fn project(event: &str, body: &[u8]) -> Option<String>;
+
+    /// Checks a delivery against the secret it should have been sent with
+    fn verify(
+        headers: &HashMap<String, String>,
+        body: &[u8],
+        secret: Option<String>,
+    ) -> Result<(), HttpResponse>;
+
+    /// Handles an event according to its type
+    async fn handle(
+        event: &str,
+        body: &[u8],
+        pool: &Data<DbPool>,
+        queue: Data<BuildQueue>,
+    ) -> actix_web::Result<Outcome>;
+}
+
+/// Receives a webhook delivery from a provider, handling it if it's authentic
+/// Every delivery is recorded, and redeliveries of ones already processed are ignored
+pub(crate) async fn receive<P: Provider>(
+    request: web::HttpRequest,
+    body: web::Bytes,
+    pool: Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<HttpResponse> {
+    let body: Vec<u8> = body.to_vec();
+    let headers = get_headers_hash_map(request.headers())?;
+    let event = P::event(&headers);
+
+    let mut delivery = Delivery::new(P::delivery_id(&headers), event, &headers, &body);
+    // The payload isn't trusted yet, it's only read to find which secret it should be sent with
+    let secret = project_secret(&pool, P::project(event, &body), P::FALLBACK_SECRET).await?;
+    let verified = P::verify(&headers, &body, secret);
+    if let Some(response) = delivery.screen(&pool, verified).await? {
+        return Ok(response);
+    }
+
+    let handled = P::handle(event, &body, &pool, queue).await;
+    delivery.finish(&pool, handled).await
+}
+
+/// Represents a commit taken from a webhook, which GitHub, GitLab and Gitea all describe alike
+#[derive(Debug, Deserialize)]
+pub(crate) struct CommitData {
+    pub(crate) id: String,
+    #[serde(default)]
+    pub(crate) added: Vec<String>,
+    #[serde(default)]
+    pub(crate) modified: Vec<String>,
+    #[serde(default)]
+    pub(crate) removed: Vec<String>,
+}
+
+/// Files added, modified or removed by any of the commits, sorted and without duplicates
+pub(crate) fn changed_files(commits: &[CommitData]) -> Vec<String> {
+    let mut files: Vec<String> = commits
+        .iter()
+        .flat_map(|commit| {
+            commit
+                .added
+                .iter()
+                .chain(&commit.modified)
+                .chain(&commit.removed)
+        })
+        .cloned()
+        .collect();
+    files.sort();
+    files.dedup();
+
+    files
+}
+
+/// Parses a payload, answering payloads which can't be parsed with a 400
+pub(crate) fn parse<T: DeserializeOwned>(body: &[u8]) -> actix_web::Result<T> {
+    serde_json::from_slice(body)
+        .map_err(|e| actix_web::error::ErrorBadRequest(format!("Invalid payload: {}", e)))
+}
+
+/// Represents a repository taken from a webhook, which GitHub and Gitea describe alike
+#[derive(Debug, Deserialize)]
+pub(crate) struct RepositoryData {
+    pub(crate) name: String,
+}
+
+/// Represents any event taken from a GitHub or Gitea webhook, for the repository it's about
+#[derive(Debug, Deserialize)]
+pub(crate) struct RepositoryEventData {
+    pub(crate) repository: RepositoryData,
+}
+
+/// Represents a create or delete event taken from a GitHub or Gitea webhook
+#[derive(Debug, Deserialize)]
+pub(crate) struct RefEventData {
+    /// Short name of the branch or tag
+    #[serde(rename = "ref")]
+    git_ref: String,
+    ref_type: String,
+    repository: RepositoryData,
+}
+
+/// Represents a pull_request event taken from a GitHub or Gitea webhook
+#[derive(Debug, Deserialize)]
+struct PullRequestEventData {
+    action: String,
+    number: i32,
+    pull_request: PullRequestData,
+    repository: RepositoryData,
+}
+
+impl PullRequestEventData {
+    /// Whether the event changes what the pull request would merge, so it should be built
+    /// GitHub sends 'synchronize' when a pull request is pushed to, Gitea 'synchronized'
+    fn needs_build(&self) -> bool {
+        matches!(
+            self.action.as_str(),
+            "opened" | "reopened" | "synchronize" | "synchronized"
+        )
+    }
+}
+
+/// Represents a pull request taken from a GitHub or Gitea webhook
+#[derive(Debug, Deserialize)]
+struct PullRequestData {
+    head: PullRequestHeadData,
+    base: PullRequestBaseData,
+}
+
+impl PullRequestData {
+    /// Whether the pull request is from a repository other than the one it's to, such as a fork
+    fn is_fork(&self) -> bool {
+        self.head.repo.as_ref().map(|repo| repo.id) != Some(self.base.repo.id)
+    }
+}
+
+/// Represents the head of a pull request taken from a GitHub or Gitea webhook
+#[derive(Debug, Deserialize)]
+struct PullRequestHeadData {
+    /// Name of the branch the pull request is from
+    #[serde(rename = "ref")]
+    git_ref: String,
+    sha: String,
+    /// Repository the pull request is from, null if it has since been deleted
+    repo: Option<PullRequestRepositoryData>,
+}
+
+/// Represents what a pull request is to be merged into, taken from a GitHub or Gitea webhook
+#[derive(Debug, Deserialize)]
+struct PullRequestBaseData {
+    repo: PullRequestRepositoryData,
+}
+
+/// Represents either repository of a pull request taken from a GitHub or Gitea webhook
+#[derive(Debug, Deserialize)]
+struct PullRequestRepositoryData {
+    id: i64,
+}
+
+/// A pull request to be built
+pub(crate) struct PullRequest {
+    pub(crate) number: i32,
+    /// Name of the branch the pull request is from
+    pub(crate) branch: String,
+    /// SHA of the head of the pull request
+    pub(crate) sha: String,
+    /// Whether the pull request is from another repository, such as a fork
+    pub(crate) fork: bool,
+}
+
+/// Gets the secret webhooks for a project are signed with
+/// Projects without their own secret, or deliveries which don't name a project, use the one in 'fallback_var'
+async fn project_secret(
+    pool: &Data<DbPool>,
+    project: Option<String>,
+    fallback_var: &'static str,
+) -> actix_web::Result<Option<String>> {
+    let secret = match project {
+        Some(project) => {
+            with_database(pool, move |database| get_webhook_secret(database, &project)).await?
+        }
+        None => None,
+    };
+
+    Ok(secret.or_else(|| env::var(fallback_var).ok()))
+}
+
+/// Runs 'work' against a project known to drovah, both as a clone in 'data/projects' and in the database
+/// 'work' is given the project's path, id and default branch
+/// Queueing a build takes a connection of its own, so 'work' is run without holding on to one
+async fn with_project<F>(
+    pool: &Data<DbPool>,
+    project: String,
+    work: F,
+) -> actix_web::Result<Outcome>
+where
+    F: FnOnce(&str, i32, String) -> Outcome + Send + 'static,
+{
+    let project_path = format!("data/projects/{}/", &project);
+    if !Path::new(&project_path).exists() {
+        return Ok(Outcome::UnknownProject);
+    }
+
+    let found = with_database(pool, move |database| {
+        let project_id = get_project_id(database, &project)?;
+        Some((project_id, get_default_branch(database, project_id)?))
+    })
+    .await?;
+
+    match found {
+        Some((project_id, default_branch)) => {
+            run_blocking(move || work(&project_path, project_id, default_branch)).await
+        }
+        None => Ok(Outcome::UnknownProject),
+    }
+}
+
+/// Queues a build, reporting any failure against the project
+fn enqueue(queue: &Arc<BuildQueue>, request: BuildRequest, project_path: &str) -> Outcome {
+    match queue.enqueue(request) {
+        Ok(build_id) => Outcome::Queued(build_id),
+        Err(e) => {
+            eprintln!("Error queueing build of {}: {}", project_path, e);
+            Outcome::Failed
+        }
+    }
+}
+
+/// Queues a build of a push to a project, whose triggers are checked once it is built
+/// 'push' is given the project's default branch, for payloads which don't name a ref,
+/// and returns None if the ref isn't something which can be built
+pub(crate) async fn queue_push<F>(
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+    project: String,
+    push: F,
+) -> actix_web::Result<Outcome>
+where
+    F: FnOnce(&str) -> Option<PushEvent> + Send + 'static,
+{
+    let queue = queue.into_inner();
+
+    with_project(
+        pool,
+        project,
+        move |project_path, project_id, default_branch| {
+            let push = match push(&default_branch) {
+                Some(push) => push,
+                None => return Outcome::Ignored("Ref is not a branch or tag, ignored"),
+            };
+
+            // Tag builds don't belong to any branch, so are recorded with an empty one
+            let (branch, tag) = match push.git_ref {
+                GitRef::Branch(branch) => (branch, None),
+                GitRef::Tag(tag) => (String::new(), Some(tag)),
+                GitRef::PullRequest(_) => {
+                    return Outcome::Ignored("Pull requests aren't pushed to")
+                }
+            };
+
+            let request = BuildRequest {
+                project_id,
+                branch,
+                commit_sha: push.after,
+                pr_number: None,
+                tag,
+                author: push.author,
+                before_sha: push.before,
+                changed_files: push.changed_files,
+            };
+
+            enqueue(&queue, request, project_path)
+        },
+    )
+    .await
+}
+
+/// Queues a build of the head of a pull request to a project
+/// Pull requests from forks are only built if the project allows it, as anyone can open one
+pub(crate) async fn queue_pull_request(
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+    project: String,
+    pull_request: PullRequest,
+) -> actix_web::Result<Outcome> {
+    let queue = queue.into_inner();
+
+    if pull_request.fork {
+        let name = project.clone();
+        let build_forks = with_database(pool, move |database| {
+            get_project(database, &name).is_some_and(|project| project.build_forks)
+        })
+        .await?;
+
+        if !build_forks {
+            return Ok(Outcome::Ignored("Pull request is from a fork, ignored"));
+        }
+    }
+
+    with_project(pool, project, move |project_path, project_id, _| {
+        let request = BuildRequest {
+            project_id,
+            branch: pull_request.branch,
+            commit_sha: Some(pull_request.sha),
+            pr_number: Some(pull_request.number),
+            tag: None,
+            author: None,
+            before_sha: None,
+            changed_files: None,
+        };
+
+        enqueue(&queue, request, project_path)
+    })
+    .await
+}
+
+/// Removes builds of a deleted branch or tag from the queue, as there is nothing left to build
+pub(crate) async fn dequeue_deleted_ref(
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+    project: String,
+    git_ref: GitRef,
+) -> actix_web::Result<Outcome> {
+    let queue = queue.into_inner();
+
+    with_project(
+        pool,
+        project,
+        move |project_path, project_id, _| match queue.dequeue_ref(project_id, &git_ref) {
+            Ok(dequeued) => Outcome::Handled(format!("Removed {} queued build(s)", dequeued)),
+            Err(e) => {
+                eprintln!("Error dequeueing builds of {}: {}", project_path, e);
+                Outcome::Failed
+            }
+        },
+    )
+    .await
+}
+
+/// Handles GitHub's and Gitea's pull_request events, building the head of pull requests as they're opened or updated
+pub(crate) async fn pull_request_event(
+    body: &[u8],
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<Outcome> {
+    let event: PullRequestEventData = parse(body)?;
+
+    if !event.needs_build() {
+        return Ok(Outcome::Ignored("Pull request event ignored"));
+    }
+
+    println!(
+        "Received pull request #{} ({}) for '{}'",
+        event.number, event.action, event.repository.name
+    );
+
+    let pull_request = PullRequest {
+        number: event.number,
+        fork: event.pull_request.is_fork(),
+        branch: event.pull_request.head.git_ref,
+        sha: event.pull_request.head.sha,
+    };
+
+    queue_pull_request(pool, queue, event.repository.name, pull_request).await
+}
+
+/// Handles GitHub's and Gitea's delete events, dropping queued builds of the deleted branch or tag
+pub(crate) async fn delete_event(
+    body: &[u8],
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<Outcome> {
+    let event: RefEventData = parse(body)?;
+
+    let git_ref = match event.ref_type.as_str() {
+        "branch" => GitRef::Branch(event.git_ref),
+        "tag" => GitRef::Tag(event.git_ref),
+        _ => return Ok(Outcome::Ignored("Ref is not a branch or tag, ignored")),
+    };
+
+    dequeue_deleted_ref(pool, queue, event.repository.name, git_ref).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pull_request_event() {
+        let pull_request = |action: &str, head_repo: &str| {
+            let body = format!(
+                r#"{{
+                    "action": "{}",
+                    "number": 7,
+                    "pull_request": {{
+                        "head": {{ "ref": "feature", "sha": "0d1a26e", "repo": {} }},
+                        "base": {{ "ref": "master", "sha": "82b3d5a", "repo": {{ "id": 1 }} }}
+                    }},
+                    "repository": {{ "name": "drovah" }}
+                }}"#,
+                action, head_repo
+            );
+            parse::<PullRequestEventData>(body.as_bytes()).unwrap()
+        };
+
+        // GitHub and Gitea spell pushes to a pull request differently
+        assert!(pull_request("synchronize", r#"{ "id": 1 }"#).needs_build());
+        assert!(pull_request("synchronized", r#"{ "id": 1 }"#).needs_build());
+        assert!(!pull_request("edited", r#"{ "id": 1 }"#).needs_build());
+
+        let event = pull_request("opened", r#"{ "id": 1 }"#);
+        assert!(!event.pull_request.is_fork());
+        assert_eq!(event.pull_request.head.git_ref, "feature");
+        assert!(pull_request("opened", r#"{ "id": 2 }"#)
+            .pull_request
+            .is_fork());
+        // The fork was deleted after the pull request was opened
+        assert!(pull_request("opened", "null").pull_request.is_fork());
+    }
+}
@@ -0,0 +1,206 @@
+use actix_web::web::{self, Data};
+use actix_web::{post, HttpResponse};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::delivery::Delivery;
+use super::{parse, queue_push, Outcome};
+use crate::models::Project;
+use crate::queue::BuildQueue;
+use crate::routes::{with_database, DbPool};
+use crate::triggers::{is_object_name, GitRef, PushEvent};
+use crate::{constant_time_eq, get_bearer_token, get_headers_hash_map, get_project};
+
+/// Represents the query string of the trigger endpoint
+/// The token can be given here for systems which can't set headers, but is then written to access logs
+#[derive(Debug, Deserialize)]
+pub struct TriggerQuery {
+    token: Option<String>,
+}
+
+/// Triggers a build of a project from any system which can send a POST request
+/// Authenticated by the project's trigger token, and read from the payload by the project's trigger mapping
+/// The token is given in an 'Authorization: Bearer <token>' header, or else in '?token=<token>'
+/// URL is <host>:<port>/api/v1/trigger/<project>
+#[post("/api/v1/trigger/{project}")]
+pub(crate) async fn trigger_build(
+    request: web::HttpRequest,
+    project: web::Path<String>,
+    query: web::Query<TriggerQuery>,
+    body: web::Bytes,
+    pool: Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<HttpResponse> {
+    let project = project.into_inner();
+    let body: Vec<u8> = body.to_vec();
+    let headers = get_headers_hash_map(request.headers())?;
+
+    let mut delivery = Delivery::new(None, "trigger", &headers, &body);
+    let name = project.clone();
+    let stored = with_database(&pool, move |database| get_project(database, &name)).await?;
+    let token = get_bearer_token(&headers).or(query.token.as_deref());
+    let verified = verify_trigger_token(stored.as_ref(), token);
+    if let Some(response) = delivery.screen(&pool, verified).await? {
+        return Ok(response);
+    }
+
+    let mapping = stored.and_then(|stored| stored.trigger_mapping);
+    let handled = trigger(project, mapping, &body, &pool, queue).await;
+    delivery.finish(&pool, handled).await
+}
+
+/// Checks the token against the project's trigger token
+/// Projects which don't exist, or have no trigger token, can't be triggered
+fn verify_trigger_token(
+    project: Option<&Project>,
+    token: Option<&str>,
+) -> Result<(), HttpResponse> {
+    let trigger_token = project.and_then(|project| project.trigger_token.as_deref());
+
+    match (trigger_token, token) {
+        (Some(trigger_token), Some(token))
+            if constant_time_eq(trigger_token.as_bytes(), token.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => Err(HttpResponse::Unauthorized().body("Invalid token")),
+    }
+}
+
+/// Queues a build of the ref and commit the payload names, or of the default branch's head if it names neither
+async fn trigger(
+    project: String,
+    mapping: Option<String>,
+    body: &[u8],
+    pool: &Data<DbPool>,
+    queue: Data<BuildQueue>,
+) -> actix_web::Result<Outcome> {
+    let mapping: TriggerMapping = match mapping {
+        Some(mapping) => serde_json::from_str(&mapping).map_err(|e| {
+            eprintln!("Invalid trigger mapping for '{}': {}", project, e);
+            actix_web::error::ErrorInternalServerError("Invalid trigger mapping")
+        })?,
+        None => TriggerMapping::default(),
+    };
+
+    let payload: Value = if body.is_empty() {
+        Value::Null
+    } else {
+        parse(body)?
+    };
+
+    if let Some(named) = lookup(&payload, mapping.project.as_deref()) {
+        if named != project {
+            return Ok(Outcome::Ignored("Payload is for another project, ignored"));
+        }
+    }
+
+    let git_ref = lookup(&payload, mapping.git_ref.as_deref());
+    let commit = lookup(&payload, mapping.commit.as_deref());
+    if commit
+        .as_deref()
+        .is_some_and(|commit| !is_object_name(commit))
+    {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Commit must be a SHA of 4 to 40 hex characters",
+        ));
+    }
+    let author = lookup(&payload, mapping.author.as_deref());
+
+    println!(
+        "Received trigger of {} for '{}'",
+        git_ref.as_deref().unwrap_or("default branch"),
+        project
+    );
+
+    queue_push(pool, queue, project, move |default_branch| {
+        let git_ref = match git_ref {
+            Some(git_ref) if git_ref.starts_with("refs/") => GitRef::parse(&git_ref)?,
+            Some(branch) => GitRef::Branch(branch),
+            None => GitRef::Branch(default_branch.to_owned()),
+        };
+
+        Some(PushEvent {
+            git_ref,
+            before: None,
+            after: commit,
+            changed_files: None,
+            author,
+        })
+    })
+    .await
+}
+
+/// Where in a trigger payload each field is read from, as JSON pointers, e.g. "/repository/name"
+/// Leaving a field out of a project's mapping reads it from the pointer of the same name, e.g. "/commit"
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct TriggerMapping {
+    /// Payloads naming a different project are ignored
+    project: Option<String>,
+    /// A full ref, or the name of a branch
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    commit: Option<String>,
+    author: Option<String>,
+}
+
+impl Default for TriggerMapping {
+    fn default() -> Self {
+        TriggerMapping {
+            project: None,
+            git_ref: Some("/ref".to_owned()),
+            commit: Some("/commit".to_owned()),
+            author: Some("/author".to_owned()),
+        }
+    }
+}
+
+/// Reads a field from the payload, None if it isn't mapped or the payload doesn't have it
+/// Numbers are taken as they're written, as some systems send ids and build numbers that way
+fn lookup(payload: &Value, pointer: Option<&str>) -> Option<String> {
+    match payload.pointer(pointer?)? {
+        Value::String(value) if !value.is_empty() => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trigger_mapping() {
+        let payload: Value = parse(
+            br#"{
+                "repo": { "name": "drovah" },
+                "build": { "ref": "refs/tags/v1.0", "sha": "0d1a26e" },
+                "author": "someone",
+                "number": 12
+            }"#,
+        )
+        .unwrap();
+
+        let mapping: TriggerMapping = serde_json::from_str(
+            r#"{ "project": "/repo/name", "ref": "/build/ref", "commit": "/build/sha" }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            lookup(&payload, mapping.project.as_deref()).as_deref(),
+            Some("drovah")
+        );
+        assert_eq!(
+            lookup(&payload, mapping.git_ref.as_deref()).as_deref(),
+            Some("refs/tags/v1.0")
+        );
+        assert_eq!(
+            lookup(&payload, mapping.author.as_deref()).as_deref(),
+            Some("someone")
+        );
+        assert_eq!(lookup(&payload, Some("/number")).as_deref(), Some("12"));
+        assert_eq!(lookup(&payload, Some("/missing")), None);
+        assert_eq!(lookup(&Value::Null, mapping.commit.as_deref()), None);
+    }
+}
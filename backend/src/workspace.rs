@@ -0,0 +1,406 @@
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::AtomicBool;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::{env, fs};
+
+use serde::Deserialize;
+
+use crate::command::{wait_for_step, StepExit};
+use crate::models::BuildStatus;
+use crate::triggers::{is_object_name, GitRef};
+
+/// When an isolated workspace is removed after its build
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WorkspaceCleanup {
+    #[default]
+    Always,
+    /// Keep the workspaces of builds which didn't pass, so they can be inspected
+    OnSuccess,
+    Never,
+}
+
+impl WorkspaceCleanup {
+    /// Whether a workspace should be removed after a build finishing with the given status
+    pub(crate) fn should_remove(self, status: BuildStatus) -> bool {
+        match self {
+            WorkspaceCleanup::Always => true,
+            WorkspaceCleanup::OnSuccess => status == BuildStatus::Passing,
+            WorkspaceCleanup::Never => false,
+        }
+    }
+}
+
+/// The directory a build runs in
+/// Either a git worktree of the project's clone at 'data/workspaces/<project>/<build number>',
+/// or the clone itself when isolation has been turned off
+pub(crate) struct Workspace {
+    path: String,
+    /// The project's clone, which owns the worktree if the workspace is isolated
+    project_path: String,
+    isolated: bool,
+}
+
+impl Workspace {
+    /// Builds directly in the project's clone, sharing it with every other build
+    /// The clone is left with the commit checked out, detached from any branch
+    pub(crate) fn shared(project_path: &str, commit: &str) -> Result<Self, String> {
+        git(&["checkout", "--force", "--detach", commit], project_path)?;
+
+        Ok(Workspace {
+            path: project_path.to_owned(),
+            project_path: project_path.to_owned(),
+            isolated: false,
+        })
+    }
+
+    /// Checks out a fresh worktree of the project's clone at the given commit for a build
+    /// Anything left over from a previous attempt at the same build is removed first
+    pub(crate) fn create(
+        project_path: &str,
+        project: &str,
+        build_number: i32,
+        commit: &str,
+    ) -> Result<Self, String> {
+        let workspace = Workspace {
+            path: format!("data/workspaces/{}/{}", project, build_number),
+            project_path: project_path.to_owned(),
+            isolated: true,
+        };
+
+        if Path::new(&workspace.path).exists() {
+            workspace.remove_worktree()?;
+        }
+
+        let absolute_path = workspace.absolute_path()?;
+        if let Some(parent) = Path::new(&absolute_path).parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Error creating {}: {}", parent.display(), e))?;
+        }
+
+        git(
+            &["worktree", "add", "--detach", &absolute_path, commit],
+            project_path,
+        )?;
+
+        Ok(workspace)
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Removes the workspace, leaving the project's clone untouched if it isn't isolated
+    pub(crate) fn remove(self) {
+        if self.isolated {
+            if let Err(e) = self.remove_worktree() {
+                eprintln!("Error removing workspace {}: {}", self.path, e);
+            }
+        }
+    }
+
+    /// Removes the worktree, falling back to deleting the directory if git no longer knows of it
+    fn remove_worktree(&self) -> Result<(), String> {
+        let absolute_path = self.absolute_path()?;
+
+        let removed = git(
+            &["worktree", "remove", "--force", &absolute_path],
+            &self.project_path,
+        );
+
+        if removed.is_err() && Path::new(&absolute_path).exists() {
+            fs::remove_dir_all(&absolute_path)
+                .map_err(|e| format!("Error deleting {}: {}", self.path, e))?;
+        }
+
+        git(&["worktree", "prune"], &self.project_path)?;
+        Ok(())
+    }
+
+    /// git resolves worktree paths relative to the clone, so they're always passed absolute
+    fn absolute_path(&self) -> Result<String, String> {
+        env::current_dir()
+            .map(|dir| dir.join(&self.path).to_string_lossy().into_owned())
+            .map_err(|e| format!("Error getting current directory: {}", e))
+    }
+}
+
+/// Fetches from the project's remote, returning the full SHA of the commit to build
+/// Without a commit, whatever the ref points at once fetched is built
+/// Commits come from webhook payloads, so anything but a SHA is refused rather than handed to git
+pub(crate) fn fetch_commit(
+    project_path: &str,
+    git_ref: &GitRef,
+    commit: Option<&str>,
+) -> Result<String, String> {
+    if let Some(commit) = commit.filter(|commit| !is_object_name(commit)) {
+        return Err(format!("'{}' isn't a commit SHA", commit));
+    }
+
+    // Tags like 'nightly' get moved upstream, which plain --tags refuses to follow
+    git(&["fetch", "--force", "--tags", "origin"], project_path)?;
+
+    // Bitbucket Cloud has no pull request refs, but pull requests from the repository's own branches
+    // have already been fetched along with every other branch, so their commit alone will do
+    let refspecs = git_ref.refspecs();
+    let fetched_ref = refspecs.is_empty()
+        || refspecs
+            .iter()
+            .any(|refspec| git(&["fetch", "origin", refspec], project_path).is_ok());
+    if !fetched_ref && commit.is_none() {
+        return Err(format!("Couldn't fetch {} from origin", git_ref.revision()));
+    }
+
+    match commit {
+        Some(commit) => {
+            let object = format!("{}^{{commit}}", commit);
+
+            // Commits no longer on any branch have to be asked for by name
+            if git(&["cat-file", "-e", &object], project_path).is_err() {
+                git(&["fetch", "origin", commit], project_path)?;
+            }
+
+            git(&["rev-parse", "--verify", &object], project_path)
+        }
+        None => git(
+            &[
+                "rev-parse",
+                "--verify",
+                &format!("{}^{{commit}}", git_ref.revision()),
+            ],
+            project_path,
+        ),
+    }
+}
+
+/// Lists the branches of the project's remote, with the SHA each points at, without fetching anything
+/// Remotes which don't answer within the timeout are given up on, along with any ssh git started
+pub(crate) fn remote_heads(
+    project_path: &str,
+    timeout: Duration,
+) -> Result<Vec<(String, String)>, String> {
+    let output = git_with_timeout(&["ls-remote", "--heads", "origin"], project_path, timeout)?;
+
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let (sha, full_ref) = line.split_once('\t')?;
+            let branch = full_ref.strip_prefix("refs/heads/")?;
+            Some((branch.to_owned(), sha.to_owned()))
+        })
+        .collect())
+}
+
+/// Lists the files changed between two commits in the project's clone
+pub(crate) fn changed_files(
+    project_path: &str,
+    from: &str,
+    to: &str,
+) -> Result<Vec<String>, String> {
+    if let Some(commit) = [from, to].iter().find(|commit| !is_object_name(commit)) {
+        return Err(format!("'{}' isn't a commit SHA", commit));
+    }
+
+    let output = git(&["diff", "--name-only", from, to], project_path)?;
+    Ok(output.lines().map(str::to_owned).collect())
+}
+
+/// Reads a file from the project's clone as it was at the given commit
+pub(crate) fn read_file_at(project_path: &str, commit: &str, file: &str) -> Result<String, String> {
+    git(&["show", &format!("{}:{}", commit, file)], project_path)
+}
+
+/// Runs git in the given directory, returning its output, or its error output if it fails
+fn git(args: &[&str], directory: &str) -> Result<String, String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(directory)
+        .output()
+        .map_err(|e| format!("Error running git, is it installed? {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Runs git like 'git', killing it and everything it started if it hasn't finished within the timeout
+/// git is never left waiting on a password prompt, as nobody is there to answer it
+fn git_with_timeout(args: &[&str], directory: &str, timeout: Duration) -> Result<String, String> {
+    let mut child = Command::new("git")
+        .args(args)
+        .current_dir(directory)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Error running git, is it installed? {}", e))?;
+
+    // The output is read as it's written, as git would block on a full pipe and never exit
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+
+    let exit = wait_for_step(
+        &mut child,
+        Some(Instant::now() + timeout),
+        &AtomicBool::new(false),
+    )
+    .map_err(|e| format!("Error waiting for git: {}", e))?;
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+
+    match exit {
+        StepExit::Exited(status) if status.success() => {
+            Ok(String::from_utf8_lossy(&stdout).trim().to_owned())
+        }
+        StepExit::Exited(_) => Err(format!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&stderr).trim()
+        )),
+        _ => Err(format!(
+            "git {} timed out after {}s",
+            args[0],
+            timeout.as_secs()
+        )),
+    }
+}
+
+/// Reads everything from a pipe on a thread of its own, giving it back once the pipe closes
+fn read_all<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = vec![];
+        if let Some(mut pipe) = pipe {
+            if let Err(e) = pipe.read_to_end(&mut output) {
+                eprintln!("Error reading output of git: {}", e);
+            }
+        }
+        output
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cleanup_policy() {
+        assert!(WorkspaceCleanup::Always.should_remove(BuildStatus::Failing));
+        assert!(WorkspaceCleanup::OnSuccess.should_remove(BuildStatus::Passing));
+        assert!(!WorkspaceCleanup::OnSuccess.should_remove(BuildStatus::TimedOut));
+        assert!(!WorkspaceCleanup::Never.should_remove(BuildStatus::Passing));
+    }
+
+    #[test]
+    fn test_fetch_commit() {
+        let dir = env::temp_dir().join(format!("drovah-fetch-{}", std::process::id()));
+        let origin = dir.join("origin");
+        let clone = dir.join("clone");
+        fs::create_dir_all(&origin).unwrap();
+        let origin = origin.to_str().unwrap();
+        let clone = clone.to_str().unwrap();
+
+        let commit = |message: &str| {
+            fs::write(format!("{}/.drovah", origin), message).unwrap();
+            git(&["add", ".drovah"], origin).unwrap();
+            let args = [
+                "-c",
+                "user.name=drovah",
+                "-c",
+                "user.email=drovah@localhost",
+                "commit",
+                "-q",
+                "-m",
+                message,
+            ];
+            git(&args, origin).unwrap();
+            git(&["rev-parse", "HEAD"], origin).unwrap()
+        };
+
+        git(&["init", "-q"], origin).unwrap();
+        let first = commit("first");
+        git(&["clone", "-q", origin, clone], dir.to_str().unwrap()).unwrap();
+        let second = commit("second");
+
+        let branch = git(&["symbolic-ref", "--short", "HEAD"], origin).unwrap();
+        assert_eq!(
+            remote_heads(clone, Duration::from_secs(30)).unwrap(),
+            vec![(branch.clone(), second.clone())]
+        );
+        let branch = GitRef::Branch(branch);
+
+        // A remote which never answers, like an ssh host dropping packets
+        let started = Instant::now();
+        let args = [
+            "-c",
+            "core.sshCommand=sleep 30;:",
+            "ls-remote",
+            "ssh://unreachable/x",
+        ];
+        let hung = git_with_timeout(&args, clone, Duration::from_millis(500));
+        assert!(hung.unwrap_err().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(fetch_commit(clone, &branch, None).unwrap(), second);
+        assert_eq!(
+            fetch_commit(clone, &branch, Some(&first[..7])).unwrap(),
+            first
+        );
+        assert_eq!(read_file_at(clone, &first, ".drovah").unwrap(), "first");
+        assert!(fetch_commit(clone, &branch, Some("0123456789abcdef")).is_err());
+        assert!(fetch_commit(clone, &branch, Some("--upload-pack=true")).is_err());
+        assert!(changed_files(clone, "--output=/dev/null", &second).is_err());
+        assert_eq!(
+            changed_files(clone, &first, &second).unwrap(),
+            vec![".drovah"]
+        );
+        assert!(fetch_commit(clone, &GitRef::Branch("missing".to_owned()), None).is_err());
+
+        git(&["tag", "nightly", &first], origin).unwrap();
+        let nightly = GitRef::Tag("nightly".to_owned());
+        assert_eq!(fetch_commit(clone, &nightly, None).unwrap(), first);
+        git(&["tag", "--force", "nightly", &second], origin).unwrap();
+        assert_eq!(fetch_commit(clone, &nightly, None).unwrap(), second);
+
+        git(&["update-ref", "refs/pull/1/head", &first], origin).unwrap();
+        assert_eq!(
+            fetch_commit(clone, &GitRef::PullRequest(1), None).unwrap(),
+            first
+        );
+
+        git(
+            &["update-ref", "refs/merge-requests/2/head", &second],
+            origin,
+        )
+        .unwrap();
+        assert_eq!(
+            fetch_commit(clone, &GitRef::PullRequest(2), None).unwrap(),
+            second
+        );
+        git(&["update-ref", "refs/pull-requests/4/from", &first], origin).unwrap();
+        assert_eq!(
+            fetch_commit(clone, &GitRef::PullRequest(4), None).unwrap(),
+            first
+        );
+        assert!(fetch_commit(clone, &GitRef::PullRequest(3), None).is_err());
+
+        git(&["checkout", "-q", "-b", "feature"], origin).unwrap();
+        let feature = commit("feature");
+        assert_eq!(
+            fetch_commit(clone, &GitRef::PullRequest(5), Some(&feature[..12])).unwrap(),
+            feature
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}